@@ -0,0 +1,83 @@
+// Aliases: 'alias ll="ls -l"'.
+//
+// Aliases are substituted by LineParser, see LineParser::finish_token().
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+static ALIASES: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+// Alias names may contain anything except blanks, quotes and the
+// chars special to the lexer or to expansion.
+fn is_alias_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.contains([
+            ' ', '\t', '\n', '\\', '\'', '"', '|', '$', '`', '/', '=', '<', '>', '&', ';', '(', ')',
+        ])
+}
+
+pub fn get(name: &str) -> Option<String> {
+    ALIASES.lock().unwrap().get(name).cloned()
+}
+
+pub fn names() -> Vec<String> {
+    ALIASES.lock().unwrap().keys().cloned().collect()
+}
+
+pub fn alias(args: &[String]) -> Result<(), i32> {
+    let print = |name: &str, value: &str| {
+        println!("alias {name}={}", crate::expand::quote(value));
+    };
+
+    if args.is_empty() || (args.len() == 1 && args[0] == "-p") {
+        for (name, value) in ALIASES.lock().unwrap().iter() {
+            print(name, value);
+        }
+        return Ok(());
+    }
+
+    let mut result = Ok(());
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, value)) => {
+                if !is_alias_name(name) {
+                    eprintln!("alias: '{name}': invalid alias name.");
+                    result = Err(1);
+                    continue;
+                }
+                ALIASES
+                    .lock()
+                    .unwrap()
+                    .insert(name.to_owned(), value.to_owned());
+            }
+            None => match get(arg) {
+                Some(value) => print(arg, value.as_str()),
+                None => {
+                    eprintln!("alias: {arg}: not found.");
+                    result = Err(1);
+                }
+            },
+        }
+    }
+    result
+}
+
+pub fn unalias(args: &[String]) -> Result<(), i32> {
+    if args.is_empty() {
+        eprintln!("unalias: usage: unalias [-a] name [name ...]");
+        return Err(1);
+    }
+    if args[0] == "-a" {
+        ALIASES.lock().unwrap().clear();
+        return Ok(());
+    }
+
+    let mut result = Ok(());
+    for arg in args {
+        if ALIASES.lock().unwrap().remove(arg.as_str()).is_none() {
+            eprintln!("unalias: {arg}: not found.");
+            result = Err(1);
+        }
+    }
+    result
+}
@@ -0,0 +1,528 @@
+// Key bindings: key sequences bound to line editing functions (see
+// term::action_names()) or to macros, i.e. text typed in their place.
+//
+// Bindings are read from $INPUTRC (~/.inputrc by default) on first use
+// and changed by the 'bind' builtin; both take readline's syntax, e.g.
+// "\e[1~": beginning-of-line, Control-t: transpose-chars or
+// "\C-xd": "date\r". Each editing mode ('set -o emacs/vi') has its own
+// keymap, so "$if mode=vi" sections apply whenever vi mode is on.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Mutex;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Target {
+    Function(String),
+    Macro(Vec<u8>),
+}
+
+type Keymap = BTreeMap<Vec<u8>, Target>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Emacs,
+    Vi,
+}
+
+const MODES: [Mode; 2] = [Mode::Emacs, Mode::Vi];
+
+impl Mode {
+    fn current() -> Self {
+        if crate::options::is_set("vi") {
+            Mode::Vi
+        } else {
+            Mode::Emacs
+        }
+    }
+
+    // Which of the keymaps (in the order of MODES) are this mode's.
+    fn only(self) -> [bool; 2] {
+        MODES.map(|mode| mode == self)
+    }
+}
+
+// The keymaps, in the order of MODES; None until the defaults and $INPUTRC
+// are loaded, see with_keymaps().
+static KEYMAPS: Mutex<Option<[Keymap; 2]>> = Mutex::new(None);
+
+const DEFAULTS: &[(&[u8], &str)] = &[
+    (b"\x01", "beginning-of-line"),
+    (b"\x02", "backward-char"),
+    (b"\x03", "interrupt"),
+    (b"\x04", "end-of-file"),
+    (b"\x05", "end-of-line"),
+    (b"\x06", "forward-char"),
+    (b"\x07", "abort"),
+    (b"\x08", "backward-delete-char"),
+    (b"\t", "complete"),
+    (b"\x0b", "kill-line"),
+    (b"\x0c", "clear-screen"),
+    (b"\r", "accept-line"),
+    (b"\x12", "reverse-search-history"),
+    (b"\x13", "forward-search-history"),
+    (b"\x14", "transpose-chars"),
+    (b"\x15", "unix-line-discard"),
+    (b"\x17", "unix-word-rubout"),
+    (b"\x18\x15", "undo"),
+    (b"\x19", "yank"),
+    (b"\x1f", "undo"),
+    (b"\x7f", "backward-delete-char"),
+    (b"\x1b[A", "previous-history"),
+    (b"\x1b[B", "next-history"),
+    (b"\x1b[C", "forward-char"),
+    (b"\x1b[D", "backward-char"),
+    (b"\x1b[3~", "delete-char"),
+    (b"\x1b[1~", "beginning-of-line"),
+    (b"\x1b[7~", "beginning-of-line"),
+    (b"\x1b[H", "beginning-of-line"),
+    (b"\x1b[4~", "end-of-line"),
+    (b"\x1b[8~", "end-of-line"),
+    (b"\x1b[F", "end-of-line"),
+    (b"\x1b[200~", "bracketed-paste-begin"),
+    (b"\x1b[1;5D", "backward-word"),
+    (b"\x1b[1;5C", "forward-word"),
+    (b"\x1b[1;3D", "backward-word"),
+    (b"\x1b[1;3C", "forward-word"),
+    (b"\x1bb", "backward-word"),
+    (b"\x1bf", "forward-word"),
+    (b"\x1bd", "kill-word"),
+    (b"\x1by", "yank-pop"),
+];
+
+// $include may nest this deep.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+fn with_keymaps<R>(f: impl FnOnce(&mut [Keymap; 2]) -> R) -> R {
+    let mut keymaps = KEYMAPS.lock().unwrap();
+    let keymaps = keymaps.get_or_insert_with(|| {
+        let mut keymaps = MODES.map(|_| {
+            DEFAULTS
+                .iter()
+                .map(|(keys, name)| (keys.to_vec(), Target::Function(name.to_string())))
+                .collect()
+        });
+        let inputrc = crate::vars::get("INPUTRC").or_else(|| {
+            crate::vars::get("HOME").map(|home| format!("{}/.inputrc", home.trim_end_matches('/')))
+        });
+        if let Some(fname) = inputrc {
+            if std::path::Path::new(fname.as_str()).exists() {
+                if let Err(err) = read_file(fname.as_str(), &mut keymaps, [true; 2], 0) {
+                    eprintln!("rush: {err}.");
+                }
+            }
+        }
+        keymaps
+    });
+    f(keymaps)
+}
+
+// The keymap of the current editing mode.
+fn with_bindings<R>(f: impl FnOnce(&mut Keymap) -> R) -> R {
+    with_keymaps(|keymaps| f(&mut keymaps[Mode::current() as usize]))
+}
+
+/// Load the bindings, so that errors in $INPUTRC are reported early.
+pub fn init() {
+    with_bindings(|_| {});
+}
+
+/// What `keys` are bound to, and whether longer bound sequences start with them.
+pub fn lookup(keys: &[u8]) -> (Option<Target>, bool) {
+    with_bindings(|bindings| {
+        let is_prefix = bindings
+            .range::<[u8], _>((Bound::Excluded(keys), Bound::Unbounded))
+            .next()
+            .is_some_and(|(longer, _)| longer.starts_with(keys));
+        (bindings.get(keys).cloned(), is_prefix)
+    })
+}
+
+/// The longest bound sequence that `keys` start with, other than `keys`
+/// themselves, and its length.
+pub fn bound_prefix(keys: &[u8]) -> Option<(Target, usize)> {
+    with_bindings(|bindings| {
+        (1..keys.len()).rev().find_map(|len| {
+            bindings
+                .get(&keys[..len])
+                .map(|target| (target.clone(), len))
+        })
+    })
+}
+
+/// The 'bind' builtin.
+pub fn bind(args: &[String]) -> Result<(), i32> {
+    let usage = || {
+        eprintln!(
+            "bind: usage: bind [-lp] [-f file] [-r keyseq] ['\"keyseq\": function-name' | '\"keyseq\": \"macro\"']"
+        );
+        Err(1)
+    };
+
+    if args.is_empty() {
+        print_bindings();
+        return Ok(());
+    }
+
+    let mut result = Ok(());
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" => print_bindings(),
+            "-l" => {
+                for name in crate::term::action_names() {
+                    println!("{name}");
+                }
+            }
+            "-f" => {
+                let Some(fname) = args.next() else {
+                    return usage();
+                };
+                let modes = Mode::current().only();
+                if let Err(err) = with_keymaps(|keymaps| read_file(fname, keymaps, modes, 0)) {
+                    eprintln!("bind: {err}.");
+                    result = Err(1);
+                }
+            }
+            "-r" => {
+                let Some(keys) = args.next() else {
+                    return usage();
+                };
+                let keys = unescape(keys.trim_matches('"'));
+                if with_bindings(|bindings| bindings.remove(&keys)).is_none() {
+                    eprintln!("bind: \"{}\": not bound.", escape(&keys));
+                    result = Err(1);
+                }
+            }
+            option if option.starts_with('-') => return usage(),
+            line => match parse_binding(line) {
+                Ok((keys, target)) => {
+                    with_bindings(|bindings| bindings.insert(keys, target));
+                }
+                Err(err) => {
+                    eprintln!("bind: {err}.");
+                    result = Err(1);
+                }
+            },
+        }
+    }
+    result
+}
+
+// In the inputrc syntax.
+fn print_bindings() {
+    with_bindings(|bindings| {
+        for (keys, target) in bindings.iter() {
+            match target {
+                Target::Function(name) => println!("\"{}\": {name}", escape(keys)),
+                Target::Macro(text) => println!("\"{}\": \"{}\"", escape(keys), escape(text)),
+            }
+        }
+    });
+}
+
+// Bind the lines of an inputrc file in the keymaps of `modes`. Lines
+// between "$if" and "$endif" are only read for rush, an editing mode
+// ("$if mode=vi") or $TERM ("$if term=xterm"); readline variables
+// ("set ...") are ignored.
+fn read_file(
+    fname: &str,
+    keymaps: &mut [Keymap; 2],
+    modes: [bool; 2],
+    depth: usize,
+) -> Result<(), String> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(format!("{fname}: too many nested $include"));
+    }
+    let text = std::fs::read_to_string(fname).map_err(|err| format!("{fname}: {err}"))?;
+
+    // The conditions of the enclosing $ifs (negated after $else), per mode.
+    let mut conditions: Vec<[bool; 2]> = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let active: [bool; 2] =
+            std::array::from_fn(|mode| modes[mode] && conditions.iter().all(|c| c[mode]));
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        } else if let Some(condition) = line.strip_prefix("$if") {
+            conditions.push(test(condition.trim()));
+        } else if line.starts_with("$else") {
+            if let Some(last) = conditions.last_mut() {
+                *last = last.map(|c| !c);
+            }
+        } else if line.starts_with("$endif") {
+            conditions.pop();
+        } else if active == [false; 2] || line.starts_with("set ") {
+            continue;
+        } else if let Some(included) = line.strip_prefix("$include") {
+            let included = match included.trim().strip_prefix("~/") {
+                Some(path) => format!("{}/{path}", crate::vars::get("HOME").unwrap_or_default()),
+                None => included.trim().to_owned(),
+            };
+            if let Err(err) = read_file(included.as_str(), keymaps, active, depth + 1) {
+                eprintln!("rush: {err}.");
+            }
+        } else {
+            match parse_binding(line) {
+                Ok((keys, target)) => {
+                    for (keymap, _) in keymaps.iter_mut().zip(active).filter(|(_, on)| *on) {
+                        keymap.insert(keys.clone(), target.clone());
+                    }
+                }
+                Err(err) => eprintln!("rush: {fname}:{}: {err}.", idx + 1),
+            }
+        }
+    }
+    Ok(())
+}
+
+// An "$if" condition, for each mode.
+fn test(condition: &str) -> [bool; 2] {
+    let result = match condition.split_once('=') {
+        Some(("mode", mode)) => match mode.trim() {
+            "emacs" => return Mode::Emacs.only(),
+            "vi" => return Mode::Vi.only(),
+            _ => false,
+        },
+        Some(("term", term)) => {
+            let current = crate::vars::get("TERM").unwrap_or_default();
+            let term = term.trim();
+            current == term || current.split('-').next() == Some(term)
+        }
+        Some(_) => false, // E.g. "version>=8".
+        None => condition.eq_ignore_ascii_case("rush"),
+    };
+    [result; 2]
+}
+
+// Parse '"keyseq": function-name', '"keyseq": "macro"' or, with a key name,
+// 'Control-x: function-name' (also Meta-, C- and M-, e.g. "M-Rubout").
+fn parse_binding(line: &str) -> Result<(Vec<u8>, Target), String> {
+    let line = line.trim();
+    let (keys, rest) = match line.strip_prefix('"') {
+        Some(quoted) => {
+            let end = closing_quote(quoted, '"').ok_or("unterminated key sequence")?;
+            (unescape(&quoted[..end]), &quoted[(end + 1)..])
+        }
+        None => {
+            let end = line.find(':').ok_or(format!("{line}: ':' expected"))?;
+            (key_name(line[..end].trim())?, &line[end..])
+        }
+    };
+    if keys.is_empty() {
+        return Err("empty key sequence".to_owned());
+    }
+    let Some(rest) = rest.trim_start().strip_prefix(':') else {
+        return Err(format!("{line}: ':' expected"));
+    };
+
+    let rest = rest.trim();
+    let target = match rest.chars().next() {
+        Some(quote @ ('"' | '\'')) => {
+            let quoted = &rest[1..];
+            let end = closing_quote(quoted, quote).ok_or("unterminated macro")?;
+            Target::Macro(unescape(&quoted[..end]))
+        }
+        _ => {
+            let name = rest.split_whitespace().next().unwrap_or_default();
+            if !crate::term::action_names().any(|action| action == name) {
+                return Err(format!("{name}: unknown function name"));
+            }
+            Target::Function(name.to_owned())
+        }
+    };
+    Ok((keys, target))
+}
+
+// The position of the unescaped `quote` in `text`.
+fn closing_quote(text: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+    for (idx, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            return Some(idx);
+        }
+    }
+    None
+}
+
+// E.g. "Control-a", "Meta-Rubout" or "TAB".
+fn key_name(name: &str) -> Result<Vec<u8>, String> {
+    let (mut control, mut meta) = (false, false);
+    let mut rest = name;
+    loop {
+        let lower = rest.to_ascii_lowercase();
+        if lower.starts_with("control-") {
+            (control, rest) = (true, &rest[8..]);
+        } else if lower.starts_with("meta-") {
+            (meta, rest) = (true, &rest[5..]);
+        } else if lower.starts_with("c-") && rest.len() > 2 {
+            (control, rest) = (true, &rest[2..]);
+        } else if lower.starts_with("m-") && rest.len() > 2 {
+            (meta, rest) = (true, &rest[2..]);
+        } else {
+            break;
+        }
+    }
+
+    let key = match rest.to_ascii_lowercase().as_str() {
+        "rubout" | "del" => 0x7f,
+        "escape" | "esc" => 0x1b,
+        "newline" | "lfd" => b'\n',
+        "return" | "ret" => b'\r',
+        "space" | "spc" => b' ',
+        "tab" => b'\t',
+        _ if rest.len() == 1 && rest.is_ascii() => rest.as_bytes()[0],
+        _ => return Err(format!("{name}: unknown key name")),
+    };
+    let key = if control { to_control(key) } else { key };
+    Ok(if meta { vec![0x1b, key] } else { vec![key] })
+}
+
+fn to_control(key: u8) -> u8 {
+    if key == b'?' {
+        0x7f
+    } else {
+        key.to_ascii_uppercase() & 0x1f
+    }
+}
+
+// Key sequences and macros: \C-x, \M-x, \e, \\, \", \', \a, \b, \d, \f,
+// \n, \r, \t, \v, \NNN (octal) and \xHH.
+fn unescape(text: &str) -> Vec<u8> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut result = vec![];
+    let mut idx = 0;
+    while idx < chars.len() {
+        idx = unescape_key(&chars, idx, &mut result);
+    }
+    result
+}
+
+// Unescape the key at chars[idx]; returns the index after it.
+fn unescape_key(chars: &[char], idx: usize, result: &mut Vec<u8>) -> usize {
+    let c = chars[idx];
+    if c != '\\' || idx + 1 == chars.len() {
+        let mut buf = [0_u8; 4];
+        result.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        return idx + 1;
+    }
+
+    let next = chars[idx + 1];
+    let prefixed = chars.get(idx + 2) == Some(&'-') && idx + 3 < chars.len();
+    let byte = match next {
+        'C' if prefixed => {
+            let mut key = vec![];
+            let end = unescape_key(chars, idx + 3, &mut key);
+            // The last byte: "\C-\M-x" is ESC, then Control-x.
+            if let Some((last, meta)) = key.split_last() {
+                result.extend_from_slice(meta);
+                result.push(to_control(*last));
+            }
+            return end;
+        }
+        'M' if prefixed => {
+            result.push(0x1b);
+            return unescape_key(chars, idx + 3, result);
+        }
+        '0'..='7' | 'x' => {
+            let (radix, start, max_len) = if next == 'x' {
+                (16, idx + 2, 2)
+            } else {
+                (8, idx + 1, 3)
+            };
+            let digits: String = chars[start..]
+                .iter()
+                .take(max_len)
+                .take_while(|c| c.is_digit(radix))
+                .collect();
+            match u8::from_str_radix(digits.as_str(), radix) {
+                Ok(byte) => {
+                    result.push(byte);
+                    return start + digits.len();
+                }
+                Err(_) => next as u8, // "\x" without digits.
+            }
+        }
+        'e' => 0x1b,
+        'a' => 0x07,
+        'b' => 0x08,
+        'd' => 0x7f,
+        'f' => 0x0c,
+        'n' => b'\n',
+        'r' => b'\r',
+        't' => b'\t',
+        'v' => 0x0b,
+        _ => {
+            let mut buf = [0_u8; 4];
+            result.extend_from_slice(next.encode_utf8(&mut buf).as_bytes());
+            return idx + 2;
+        }
+    };
+    result.push(byte);
+    idx + 2
+}
+
+// The reverse of unescape().
+fn escape(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    for b in bytes {
+        match *b {
+            0x1b => result.push_str("\\e"),
+            0x7f => result.push_str("\\C-?"),
+            b'\\' => result.push_str("\\\\"),
+            b'"' => result.push_str("\\\""),
+            1..=26 => result.push_str(format!("\\C-{}", (b'a' + b - 1) as char).as_str()),
+            0..=0x1f => result.push_str(format!("\\C-{}", (b + 0x40) as char).as_str()),
+            0x80.. => result.push_str(format!("\\x{b:02x}").as_str()),
+            b => result.push(b as char),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind_line(line: &str) {
+        bind(&[line.to_owned()]).unwrap();
+    }
+
+    fn function(name: &str) -> Target {
+        Target::Function(name.to_owned())
+    }
+
+    #[test]
+    fn printable_prefix() {
+        bind_line(r#""jk": vi-movement-mode"#);
+        // "j" alone is not bound (it inserts itself), but starts "jk".
+        assert_eq!(lookup(b"j"), (None, true));
+        assert_eq!(lookup(b"jk"), (Some(function("vi-movement-mode")), false));
+        // "ja": nothing bound to run first, so "j" is taken on its own.
+        assert_eq!(lookup(b"ja"), (None, false));
+        assert!(bound_prefix(b"ja").is_none());
+    }
+
+    #[test]
+    fn bound_prefixes() {
+        bind_line(r#""\C-x": "X""#);
+        bind_line(r#""\C-x\C-e": end-of-line"#);
+        // Ambiguous: bound, and the start of longer sequences.
+        assert_eq!(lookup(b"\x18"), (Some(Target::Macro(b"X".to_vec())), true));
+        assert_eq!(lookup(b"\x18\x05"), (Some(function("end-of-line")), false));
+        // Ctrl-X, then an unrelated key: run the Ctrl-X binding, keep the key.
+        assert_eq!(lookup(b"\x18a"), (None, false));
+        assert_eq!(
+            bound_prefix(b"\x18a"),
+            Some((Target::Macro(b"X".to_vec()), 1))
+        );
+        assert_eq!(
+            bound_prefix(b"\x18\x05a"),
+            Some((function("end-of-line"), 2))
+        );
+    }
+}
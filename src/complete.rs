@@ -0,0 +1,673 @@
+// Tab completion: command names in command position, $VAR names
+// after '$', and elsewhere either what a 'complete' specification
+// registered for the command says or filesystem paths.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+pub struct Candidate {
+    replacement: String, // The (quoted) text replacing the word.
+    pub display: String, // What to show in the candidate list.
+    terminator: String,  // Appended if this is the only candidate.
+}
+
+pub struct Completion {
+    pub start: usize, // Where the completed word starts in the line (in bytes).
+    word: String,     // The word as typed, up to the cursor.
+    pub candidates: Vec<Candidate>,
+}
+
+impl Completion {
+    /// The text to replace the word with: the only candidate, or the
+    /// common prefix of all candidates if it extends the word.
+    pub fn insertion(&self) -> Option<String> {
+        match self.candidates.len() {
+            0 => None,
+            1 => {
+                let candidate = &self.candidates[0];
+                Some(format!("{}{}", candidate.replacement, candidate.terminator))
+            }
+            _ => {
+                let mut prefix = self.candidates[0].replacement.as_str();
+                for candidate in &self.candidates[1..] {
+                    let len = prefix
+                        .char_indices()
+                        .zip(candidate.replacement.chars())
+                        .find(|((_, a), b)| a != b)
+                        .map_or(
+                            prefix.len().min(candidate.replacement.len()),
+                            |((idx, _), _)| idx,
+                        );
+                    prefix = &prefix[..len];
+                }
+                if prefix.len() > self.word.len() {
+                    Some(prefix.to_owned())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+// Escape `s` for insertion into a word, inside `quote` if it is open.
+fn escape(s: &str, quote: Option<char>) -> String {
+    let mut result = String::new();
+    for c in s.chars() {
+        let special = match quote {
+            // Nothing escapes in single quotes: close them around the quote.
+            Some('\'') if c == '\'' => {
+                result.push_str("'\\''");
+                continue;
+            }
+            Some('\'') => false,
+            Some(_) => "\"\\$`".contains(c),
+            None => " \t\n\\'\"|$~{}*?[]<>&;()#`=".contains(c),
+        };
+        if special {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+// Escape `name` to replace `typed`, the part of a word after `before`.
+fn requote(before: &str, typed: &str, name: &str) -> String {
+    let (_, _, quote_before) = scan(before);
+    let (_, _, quote) = scan(format!("{before}{typed}").as_str());
+    match quote {
+        // The quote was opened in `typed`: reopen it.
+        Some(q) if quote_before.is_none() => format!("{before}{q}{}", escape(name, quote)),
+        _ => format!("{before}{}", escape(name, quote)),
+    }
+}
+
+// The value of a partially typed word, e.g. "~/my\ fi" => "/home/me/my fi".
+fn unquote(word: &str) -> String {
+    crate::expand::expand_words(&[word.to_owned()], &[])
+        .pop()
+        .unwrap_or_default()
+}
+
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+    }
+    #[cfg(not(unix))]
+    {
+        metadata.is_file()
+    }
+}
+
+/// Whether the (quoted) command word can be run: a builtin, an alias, or
+/// an executable, in $PATH unless the word is a path.
+pub fn is_command(word: &str) -> bool {
+    let name = unquote(word);
+    if name.is_empty() {
+        return false;
+    }
+    if name.contains('/') {
+        return std::fs::metadata(name).is_ok_and(|m| is_executable(&m));
+    }
+    if crate::exec::BUILTINS.contains(&name.as_str())
+        || crate::term::LOCAL_COMMANDS.contains(&name.as_str())
+        || crate::alias::get(name.as_str()).is_some()
+    {
+        return true;
+    }
+
+    let path = crate::vars::get("PATH").unwrap_or_default();
+    path.split(':').filter(|dir| !dir.is_empty()).any(|dir| {
+        std::fs::metadata(Path::new(dir).join(name.as_str())).is_ok_and(|m| is_executable(&m))
+    })
+}
+
+// Complete a path; `filter` is given the path and whether it is a directory.
+fn complete_path(
+    word: &str,
+    quote: Option<char>,
+    filter: &dyn Fn(&Path, bool) -> bool,
+) -> Vec<Candidate> {
+    let (dir_raw, file_raw) = match word.rfind('/') {
+        Some(idx) => word.split_at(idx + 1),
+        None => ("", word),
+    };
+    let dir = if dir_raw.is_empty() {
+        ".".to_owned()
+    } else {
+        unquote(dir_raw)
+    };
+    let prefix = unquote(file_raw);
+
+    let Ok(entries) = std::fs::read_dir(Path::new(dir.as_str())) else {
+        return vec![];
+    };
+
+    let mut result = vec![];
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(prefix.as_str()) || (name.starts_with('.') && !prefix.starts_with('.'))
+        {
+            continue;
+        }
+        let path = entry.path();
+        let is_dir = std::fs::metadata(&path).is_ok_and(|m| m.is_dir());
+        if !filter(path.as_path(), is_dir) {
+            continue;
+        }
+
+        let mut candidate = Candidate {
+            replacement: requote(dir_raw, file_raw, name.as_str()),
+            display: name,
+            terminator: format!("{} ", quote.map(String::from).unwrap_or_default()),
+        };
+        if is_dir {
+            candidate.replacement.push('/');
+            candidate.display.push('/');
+            candidate.terminator.clear();
+        }
+        result.push(candidate);
+    }
+    result.sort_by(|a, b| a.display.cmp(&b.display));
+    result
+}
+
+fn complete_command(word: &str, quote: Option<char>) -> Vec<Candidate> {
+    if word.contains('/') {
+        return complete_path(word, quote, &|path, is_dir| {
+            is_dir || std::fs::metadata(path).is_ok_and(|m| is_executable(&m))
+        });
+    }
+
+    let prefix = unquote(word);
+    let mut names: Vec<String> = crate::exec::BUILTINS
+        .iter()
+        .chain(crate::term::LOCAL_COMMANDS.iter())
+        .map(|name| name.to_string())
+        .chain(crate::alias::names())
+        .filter(|name| name.starts_with(prefix.as_str()))
+        .collect();
+
+    let path = crate::vars::get("PATH").unwrap_or_default();
+    for dir in path.split(':').filter(|dir| !dir.is_empty()) {
+        let Ok(entries) = std::fs::read_dir(Path::new(dir)) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(prefix.as_str())
+                && std::fs::metadata(entry.path()).is_ok_and(|m| is_executable(&m))
+            {
+                names.push(name);
+            }
+        }
+    }
+
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .map(|name| Candidate {
+            replacement: requote("", word, name.as_str()),
+            display: name,
+            terminator: format!("{} ", quote.map(String::from).unwrap_or_default()),
+        })
+        .collect()
+}
+
+// "$HO" or "${HO" at the end of the word: complete the variable name.
+fn complete_var(word: &str) -> Option<Vec<Candidate>> {
+    let dollar = word.rfind('$')?;
+    let (braced, name_start) = if word[dollar..].starts_with("${") {
+        (true, dollar + 2)
+    } else {
+        (false, dollar + 1)
+    };
+    let prefix = &word[name_start..];
+    if !prefix
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+    {
+        return None;
+    }
+
+    Some(
+        crate::vars::names()
+            .into_iter()
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| Candidate {
+                replacement: format!(
+                    "{}{name}{}",
+                    &word[..name_start],
+                    if braced { "}" } else { "" }
+                ),
+                display: name,
+                terminator: String::new(),
+            })
+            .collect(),
+    )
+}
+
+// Split `line` the way LineParser does: return where the last word
+// starts, the words of the last command before it, and the open quote.
+fn scan(line: &str) -> (usize, Vec<&str>, Option<char>) {
+    let mut word_start = 0;
+    let mut prev_words: Vec<&str> = vec![];
+    let mut quote: Option<char> = None;
+    let mut escape = false;
+    for (idx, c) in line.char_indices() {
+        if escape {
+            escape = false;
+            continue;
+        }
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(q) => escape = c == '\\' && q == '"',
+            None => match c {
+                '\\' => escape = true,
+                '\'' | '"' => quote = Some(c),
+                ' ' | '\t' | '\n' => {
+                    if idx > word_start {
+                        prev_words.push(&line[word_start..idx]);
+                    }
+                    word_start = idx + 1;
+                }
+                '|' => {
+                    prev_words.clear();
+                    word_start = idx + 1;
+                }
+                _ => {}
+            },
+        }
+    }
+
+    (word_start, prev_words, quote)
+}
+
+/// Complete the word that ends at `pos` in `line`.
+pub fn complete(line: &str, pos: usize) -> Completion {
+    let line = &line[..pos];
+    let (word_start, prev_words, quote) = scan(line);
+    let word = &line[word_start..];
+    let command_name = prev_words
+        .iter()
+        .find(|word| !crate::line_parser::is_assignment(word));
+
+    let candidates = if let Some(candidates) = complete_var(word).filter(|_| quote != Some('\'')) {
+        candidates
+    } else if let Some(command_name) = command_name {
+        let command_name = unquote(command_name);
+        let spec = SPECS.lock().unwrap().get(command_name.as_str()).cloned();
+        match spec {
+            Some(spec) => {
+                let prev_word = unquote(prev_words.last().unwrap());
+                let context = Context {
+                    line,
+                    command_name: command_name.as_str(),
+                    prev_word: prev_word.as_str(),
+                };
+                spec.candidates(word, quote, &context)
+            }
+            None => complete_path(word, quote, &|_, _| true),
+        }
+    } else {
+        complete_command(word, quote)
+    };
+
+    Completion {
+        start: word_start,
+        word: word.to_owned(),
+        candidates,
+    }
+}
+
+// Programmable completion: 'complete -W "start stop status" service'.
+
+#[derive(Clone, Default)]
+struct CompSpec {
+    aliases: bool,           // -a
+    commands: bool,          // -c
+    directories: bool,       // -d
+    files: bool,             // -f
+    variables: bool,         // -v
+    words: Option<String>,   // -W wordlist
+    command: Option<String>, // -C command
+    filter: Option<String>,  // -X pattern: remove what matches, or what doesn't if "!pattern".
+    default: bool,           // -o default: complete paths if nothing else matches.
+    nospace: bool,           // -o nospace
+    plusdirs: bool,          // -o plusdirs: add directories after filtering.
+}
+
+static SPECS: Mutex<BTreeMap<String, CompSpec>> = Mutex::new(BTreeMap::new());
+
+// What -C commands are told about the line being completed.
+struct Context<'a> {
+    line: &'a str,
+    command_name: &'a str,
+    prev_word: &'a str,
+}
+
+// Match `text` against a glob pattern with '*', '?' and '[...]'.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => (0..=text.len()).any(|skip| glob_match(&pattern[1..], &text[skip..])),
+        Some('?') => !text.is_empty() && glob_match(&pattern[1..], &text[1..]),
+        Some('[') => {
+            let Some(close) = pattern.iter().skip(2).position(|c| *c == ']') else {
+                return text.first() == Some(&'[') && glob_match(&pattern[1..], &text[1..]);
+            };
+            let Some(c) = text.first() else {
+                return false;
+            };
+            let mut class = &pattern[1..(close + 2)];
+            let negated = class.first() == Some(&'!') || class.first() == Some(&'^');
+            if negated {
+                class = &class[1..];
+            }
+            let mut matched = false;
+            let mut idx = 0;
+            while idx < class.len() {
+                if idx + 2 < class.len() && class[idx + 1] == '-' {
+                    matched |= class[idx] <= *c && *c <= class[idx + 2];
+                    idx += 3;
+                } else {
+                    matched |= class[idx] == *c;
+                    idx += 1;
+                }
+            }
+            matched != negated && glob_match(&pattern[(close + 3)..], &text[1..])
+        }
+        Some(p) => text.first() == Some(p) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+// Split a -W wordlist on IFS and expand the words, as bash does: the
+// words are not a command line, so there are no aliases or comments.
+fn split_words(words: &str) -> Vec<String> {
+    let ifs = crate::vars::get("IFS").unwrap_or_else(|| " \t\n".to_owned());
+    let words: Vec<String> = words
+        .split(|c| ifs.contains(c))
+        .filter(|word| !word.is_empty())
+        .map(str::to_owned)
+        .collect();
+    crate::expand::expand_words(&words, &[])
+}
+
+// Run a -C command; its output lines are the candidates. As in bash, the
+// command gets the command name, the word and the previous word as args.
+fn run_command(command: &str, word: &str, context: &Context) -> Vec<String> {
+    let mut parser = crate::line_parser::LineParser::new();
+    let argv = match parser.parse_line(command) {
+        Some(commands) => crate::expand::expand_words(&commands.concat(), &[]),
+        None => vec![],
+    };
+    if argv.is_empty() {
+        return vec![];
+    }
+
+    let output = std::process::Command::new(argv[0].as_str())
+        .args(&argv[1..])
+        .args([context.command_name, word, context.prev_word])
+        .env_clear()
+        .envs(crate::vars::exported())
+        .env("COMP_LINE", context.line)
+        .env("COMP_POINT", context.line.len().to_string())
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output();
+    match output {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| line.to_owned())
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+impl CompSpec {
+    fn candidates(&self, word: &str, quote: Option<char>, context: &Context) -> Vec<Candidate> {
+        let prefix = unquote(word);
+        let terminator = format!("{} ", quote.map(String::from).unwrap_or_default());
+
+        let mut names = vec![];
+        if self.aliases {
+            names.append(&mut crate::alias::names());
+        }
+        if self.variables {
+            names.append(&mut crate::vars::names());
+        }
+        if let Some(words) = &self.words {
+            names.append(&mut split_words(words.as_str()));
+        }
+        if let Some(command) = &self.command {
+            names.append(&mut run_command(command.as_str(), prefix.as_str(), context));
+        }
+
+        let mut result: Vec<Candidate> = names
+            .into_iter()
+            .filter(|name| name.starts_with(prefix.as_str()))
+            .map(|name| Candidate {
+                replacement: requote("", word, name.as_str()),
+                display: name,
+                terminator: terminator.clone(),
+            })
+            .collect();
+        if self.commands {
+            result.append(&mut complete_command(word, quote));
+        }
+        if self.files {
+            result.append(&mut complete_path(word, quote, &|_, _| true));
+        } else if self.directories {
+            result.append(&mut complete_path(word, quote, &|_, is_dir| is_dir));
+        }
+
+        if let Some(filter) = &self.filter {
+            let (negated, pattern) = match filter.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, filter.as_str()),
+            };
+            let pattern: Vec<char> = pattern.chars().collect();
+            result.retain(|candidate| {
+                let name: Vec<char> = candidate.display.trim_end_matches('/').chars().collect();
+                glob_match(&pattern, &name) == negated
+            });
+        }
+
+        if self.plusdirs && !self.directories {
+            result.append(&mut complete_path(word, quote, &|_, is_dir| is_dir));
+        }
+        if result.is_empty() && self.default {
+            result = complete_path(word, quote, &|_, _| true);
+        }
+
+        if self.nospace {
+            for candidate in &mut result {
+                candidate.terminator.clear();
+            }
+        }
+        result.sort_by(|a, b| a.display.cmp(&b.display));
+        result.dedup_by(|a, b| a.display == b.display);
+        result
+    }
+
+    fn print(&self, name: &str) {
+        let mut line = String::from("complete");
+        for (set, flag) in [
+            (self.aliases, "-a"),
+            (self.commands, "-c"),
+            (self.directories, "-d"),
+            (self.files, "-f"),
+            (self.variables, "-v"),
+            (self.default, "-o default"),
+            (self.nospace, "-o nospace"),
+            (self.plusdirs, "-o plusdirs"),
+        ] {
+            if set {
+                line.push(' ');
+                line.push_str(flag);
+            }
+        }
+        for (value, flag) in [
+            (&self.words, "-W"),
+            (&self.command, "-C"),
+            (&self.filter, "-X"),
+        ] {
+            if let Some(value) = value {
+                line.push_str(format!(" {flag} {}", crate::expand::quote(value)).as_str());
+            }
+        }
+        println!("{line} {}", crate::expand::quote(name));
+    }
+}
+
+// The options of complete and compgen.
+struct SpecArgs {
+    spec: CompSpec,
+    print: bool,         // -p
+    remove: bool,        // -r
+    others: Vec<String>, // Command names (complete) or the word (compgen).
+}
+
+fn parse_spec_args(cmd: &str, args: &[String]) -> Result<SpecArgs, i32> {
+    let mut result = SpecArgs {
+        spec: CompSpec::default(),
+        print: false,
+        remove: false,
+        others: vec![],
+    };
+
+    let mut idx = 0;
+    while idx < args.len() {
+        let arg = args[idx].as_str();
+        idx += 1;
+        if arg == "--" {
+            result.others.extend_from_slice(&args[idx..]);
+            break;
+        }
+        if !arg.starts_with('-') || arg.len() < 2 {
+            result.others.push(arg.to_owned());
+            continue;
+        }
+
+        for (pos, flag) in arg.char_indices().skip(1) {
+            let spec = &mut result.spec;
+            match flag {
+                'a' => spec.aliases = true,
+                'c' => spec.commands = true,
+                'd' => spec.directories = true,
+                'f' => spec.files = true,
+                'v' => spec.variables = true,
+                'p' => result.print = true,
+                'r' => result.remove = true,
+                'W' | 'C' | 'X' | 'o' | 'F' => {
+                    // The value is either the rest of this arg or the next arg.
+                    let value = if pos + 1 < arg.len() {
+                        arg[(pos + 1)..].to_owned()
+                    } else if idx < args.len() {
+                        idx += 1;
+                        args[idx - 1].clone()
+                    } else {
+                        eprintln!("{cmd}: -{flag}: option requires an argument.");
+                        return Err(2);
+                    };
+                    match flag {
+                        'W' => spec.words = Some(value),
+                        'C' => spec.command = Some(value),
+                        'X' => spec.filter = Some(value),
+                        'o' => match value.as_str() {
+                            "default" | "bashdefault" => spec.default = true,
+                            "dirnames" => spec.directories = true,
+                            "filenames" => {}
+                            "nospace" => spec.nospace = true,
+                            "plusdirs" => spec.plusdirs = true,
+                            _ => {
+                                eprintln!("{cmd}: {value}: invalid option name.");
+                                return Err(2);
+                            }
+                        },
+                        _ => {
+                            eprintln!("{cmd}: -F: shell functions are not supported, use -C.");
+                            return Err(2);
+                        }
+                    }
+                    break;
+                }
+                _ => {
+                    eprintln!("{cmd}: -{flag}: invalid option.");
+                    return Err(2);
+                }
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// complete [-acdfv] [-o option] [-W wordlist] [-C command] [-X filter] name ...
+/// complete -p [name ...]
+/// complete -r [name ...]
+pub fn complete_builtin(args: &[String]) -> Result<(), i32> {
+    let args = parse_spec_args("complete", args)?;
+    let mut specs = SPECS.lock().unwrap();
+
+    if args.remove {
+        if args.others.is_empty() {
+            specs.clear();
+        }
+        for name in &args.others {
+            specs.remove(name.as_str());
+        }
+        return Ok(());
+    }
+
+    if args.print || args.others.is_empty() {
+        let mut result = Ok(());
+        if args.others.is_empty() {
+            for (name, spec) in specs.iter() {
+                spec.print(name.as_str());
+            }
+        }
+        for name in &args.others {
+            match specs.get(name.as_str()) {
+                Some(spec) => spec.print(name.as_str()),
+                None => {
+                    eprintln!("complete: {name}: no completion specification.");
+                    result = Err(1);
+                }
+            }
+        }
+        return result;
+    }
+
+    for name in args.others {
+        specs.insert(name, args.spec.clone());
+    }
+    Ok(())
+}
+
+/// compgen [-acdfv] [-o option] [-W wordlist] [-C command] [-X filter] [word]
+pub fn compgen(args: &[String]) -> Result<(), i32> {
+    let args = parse_spec_args("compgen", args)?;
+    let word = args.others.last().map_or("", |word| word.as_str());
+    let context = Context {
+        line: word,
+        command_name: "compgen",
+        prev_word: "",
+    };
+
+    let candidates = args
+        .spec
+        .candidates(crate::expand::quote(word).as_str(), None, &context);
+    if candidates.is_empty() {
+        return Err(1);
+    }
+    for candidate in candidates {
+        println!("{}", candidate.display.trim_end_matches('/'));
+    }
+    Ok(())
+}
@@ -0,0 +1,241 @@
+// The directory stack: cd, pushd, popd, dirs.
+//
+// As in bash, the top of the stack (entry 0) is always the current
+// directory, so DIR_STACK only holds the entries below it, nearest first.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+static DIR_STACK: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+fn current_dir() -> PathBuf {
+    std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/"))
+}
+
+fn home_dir() -> Option<PathBuf> {
+    crate::vars::get("HOME").map(PathBuf::from)
+}
+
+// Replace the HOME prefix with '~', as dirs does by default.
+fn abbreviate(path: &Path) -> String {
+    if let Some(home) = home_dir() {
+        if let Ok(rest) = path.strip_prefix(&home) {
+            if rest.as_os_str().is_empty() {
+                return "~".to_owned();
+            }
+            return format!("~/{}", rest.display());
+        }
+    }
+    path.display().to_string()
+}
+
+// The whole stack, with the current directory on top.
+fn full_stack() -> Vec<PathBuf> {
+    let mut result = vec![current_dir()];
+    result.extend(DIR_STACK.lock().unwrap().iter().cloned());
+    result
+}
+
+// Parse "+N" or "-N" into an index into the full stack of `len` entries.
+fn parse_index(arg: &str, len: usize) -> Option<usize> {
+    let (from_top, num) = if let Some(num) = arg.strip_prefix('+') {
+        (true, num)
+    } else {
+        (false, arg.strip_prefix('-')?)
+    };
+
+    let num = num.parse::<usize>().ok()?;
+    if num >= len {
+        return None;
+    }
+    if from_top {
+        Some(num)
+    } else {
+        Some(len - 1 - num)
+    }
+}
+
+fn is_index(arg: &str) -> bool {
+    arg.len() > 1
+        && (arg.starts_with('+') || arg.starts_with('-'))
+        && arg.as_bytes()[1..].iter().all(|b| b.is_ascii_digit())
+}
+
+/// Changes the current directory, keeping PWD and OLDPWD up to date.
+pub fn chdir(dir: &Path) -> Result<(), i32> {
+    let old = current_dir();
+    if let Err(e) = std::env::set_current_dir(dir) {
+        println!("rush: {}: {e}", dir.display());
+        return Err(1);
+    }
+    crate::vars::set("OLDPWD", old.to_string_lossy().as_ref())?;
+    crate::vars::set("PWD", current_dir().to_string_lossy().as_ref())
+}
+
+/// The number of entries on the stack below the current directory.
+pub fn depth() -> usize {
+    DIR_STACK.lock().unwrap().len()
+}
+
+pub fn cd(args: &[String]) -> Result<(), i32> {
+    match args.len() {
+        0 => match home_dir() {
+            Some(home) => chdir(home.as_path()),
+            None => {
+                println!("cd: HOME not set.");
+                Err(1)
+            }
+        },
+        1 if args[0] == "-" => match crate::vars::get("OLDPWD") {
+            Some(old) => {
+                chdir(Path::new(&old))?;
+                println!("{}", current_dir().display());
+                Ok(())
+            }
+            None => {
+                println!("cd: OLDPWD not set.");
+                Err(1)
+            }
+        },
+        1 => chdir(Path::new(args[0].as_str())),
+        _ => {
+            println!("cd: too many arguments.");
+            Err(1)
+        }
+    }
+}
+
+pub fn pushd(args: &[String]) -> Result<(), i32> {
+    if args.len() > 1 {
+        println!("pushd: too many arguments.");
+        return Err(1);
+    }
+
+    if args.is_empty() {
+        // Exchange the top two entries.
+        let top = match DIR_STACK.lock().unwrap().first() {
+            Some(top) => top.clone(),
+            None => {
+                println!("pushd: no other directory.");
+                return Err(1);
+            }
+        };
+        let cwd = current_dir();
+        chdir(top.as_path())?;
+        DIR_STACK.lock().unwrap()[0] = cwd;
+        return dirs(&[]);
+    }
+
+    let arg = args[0].as_str();
+    if is_index(arg) {
+        // Rotate the stack so that the Nth entry is on top.
+        let mut stack = full_stack();
+        let idx = match parse_index(arg, stack.len()) {
+            Some(idx) => idx,
+            None => {
+                println!("pushd: {arg}: directory stack index out of range.");
+                return Err(1);
+            }
+        };
+        stack.rotate_left(idx);
+        chdir(stack[0].as_path())?;
+        *DIR_STACK.lock().unwrap() = stack.split_off(1);
+        return dirs(&[]);
+    }
+
+    let cwd = current_dir();
+    chdir(Path::new(arg))?;
+    DIR_STACK.lock().unwrap().insert(0, cwd);
+    dirs(&[])
+}
+
+pub fn popd(args: &[String]) -> Result<(), i32> {
+    if args.len() > 1 {
+        println!("popd: too many arguments.");
+        return Err(1);
+    }
+
+    let len = depth() + 1;
+    if len == 1 {
+        println!("popd: directory stack empty.");
+        return Err(1);
+    }
+
+    let idx = match args.first() {
+        None => 0,
+        Some(arg) => match parse_index(arg.as_str(), len) {
+            Some(idx) => idx,
+            None => {
+                println!("popd: {arg}: invalid argument or index out of range.");
+                return Err(1);
+            }
+        },
+    };
+
+    if idx == 0 {
+        let top = DIR_STACK.lock().unwrap()[0].clone();
+        chdir(top.as_path())?;
+        DIR_STACK.lock().unwrap().remove(0);
+    } else {
+        DIR_STACK.lock().unwrap().remove(idx - 1);
+    }
+    dirs(&[])
+}
+
+pub fn dirs(args: &[String]) -> Result<(), i32> {
+    let mut verbose = false;
+    let mut one_per_line = false;
+    let mut long = false;
+    let mut index = None;
+
+    for arg in args {
+        match arg.as_str() {
+            "-c" => {
+                DIR_STACK.lock().unwrap().clear();
+                return Ok(());
+            }
+            "-v" => verbose = true,
+            "-p" => one_per_line = true,
+            "-l" => long = true,
+            arg if is_index(arg) => match parse_index(arg, depth() + 1) {
+                Some(idx) => index = Some(idx),
+                None => {
+                    println!("dirs: {arg}: directory stack index out of range.");
+                    return Err(1);
+                }
+            },
+            arg => {
+                println!("dirs: {arg}: invalid option.");
+                println!("dirs: usage: dirs [-clpv] [+N] [-N]");
+                return Err(1);
+            }
+        }
+    }
+
+    let entries: Vec<String> = full_stack()
+        .iter()
+        .map(|path| {
+            if long {
+                path.display().to_string()
+            } else {
+                abbreviate(path)
+            }
+        })
+        .collect();
+
+    if let Some(idx) = index {
+        println!("{}", entries[idx]);
+    } else if verbose {
+        for (idx, entry) in entries.iter().enumerate() {
+            println!("{idx:2}  {entry}");
+        }
+    } else if one_per_line {
+        for entry in &entries {
+            println!("{entry}");
+        }
+    } else {
+        println!("{}", entries.join(" "));
+    }
+
+    Ok(())
+}
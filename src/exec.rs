@@ -1,27 +1,27 @@
-use std::{path::Path, process::Stdio};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
 
-fn is_var(token: &str) -> bool {
-    if token.is_empty() || token.len() != token.trim().len() {
-        return false;
-    }
+/// Commands run by the shell itself (see also term::LOCAL_COMMANDS).
+pub const BUILTINS: &[&str] = &[
+    "alias", "bind", "cd", "compgen", "complete", "dirs", "env", "exit", "export", "history",
+    "popd", "pushd", "quit", "readonly", "set", "unalias", "unset",
+];
 
-    if !token.is_ascii() {
-        return false;
-    }
+static LAST_STATUS: Mutex<(i32, Duration)> = Mutex::new((0, Duration::ZERO));
 
-    let first: char = token.as_bytes()[0].into();
-    if !first.is_alphabetic() {
-        return false;
-    }
+/// The exit status of the last command run interactively ($?).
+pub fn last_status() -> i32 {
+    LAST_STATUS.lock().unwrap().0
+}
 
-    for b in &token.as_bytes()[1..] {
-        let c: char = (*b).into();
-        if !c.is_alphanumeric() {
-            return false;
-        }
-    }
+/// How long the last command ran.
+pub fn last_duration() -> Duration {
+    LAST_STATUS.lock().unwrap().1
+}
 
-    true
+pub fn set_last_status(status: i32, duration: Duration) {
+    *LAST_STATUS.lock().unwrap() = (status & 0xff, duration); // As a process would see it.
 }
 
 fn take_env(command: &[String]) -> Option<(&str, &str)> {
@@ -31,7 +31,7 @@ fn take_env(command: &[String]) -> Option<(&str, &str)> {
 
     let cmd = command[0].as_str().trim();
     if let Some((k, v)) = cmd.split_once('=') {
-        if is_var(k) {
+        if crate::vars::is_name(k) {
             Some((k, v))
         } else {
             None
@@ -41,28 +41,11 @@ fn take_env(command: &[String]) -> Option<(&str, &str)> {
     }
 }
 
-fn apply_global_env(env: &Vec<(&str, &str)>) {
+fn apply_global_env(env: &Vec<(&str, String)>) -> Result<(), i32> {
     for (k, v) in env {
-        std::env::set_var(k, v);
+        crate::vars::set(k, v)?;
     }
-}
-
-fn process_vars(tokens: &[String], _env: &[(&str, &str)], args: &[String]) -> Vec<String> {
-    // We should do a proper language interpreter with AST later.
-    // For now we have something simple to bootstrap things.
-
-    let mut result = Vec::new();
-    for token in tokens {
-        if token.as_str() == "$@" {
-            for arg in &args[1..] {
-                result.push(arg.clone());
-            }
-        } else {
-            result.push(token.clone());
-        }
-    }
-
-    result
+    Ok(())
 }
 
 pub fn run(commands: Vec<Vec<String>>, global: bool, args: &[String]) -> Result<(), i32> {
@@ -80,16 +63,16 @@ pub fn run(commands: Vec<Vec<String>>, global: bool, args: &[String]) -> Result<
         // For now we have something simple to bootstrap things.
 
         // Process commands like `A=B do_something`.
-        let mut env: Vec<(&str, &str)> = vec![];
-        while let Some(k_v) = take_env(command) {
-            env.push(k_v);
+        let mut env: Vec<(&str, String)> = vec![];
+        while let Some((k, v)) = take_env(command) {
+            env.push((k, crate::expand::expand_assignment(v, args)));
             command = &command[1..];
         }
 
         if command.is_empty() {
             if global {
                 if idx == 0 && commands.len() == 1 {
-                    apply_global_env(&env);
+                    apply_global_env(&env)?;
                 } else {
                     println!("Error: cannot set global environment variable in a subcommand.");
                     return Err(-1);
@@ -98,28 +81,77 @@ pub fn run(commands: Vec<Vec<String>>, global: bool, args: &[String]) -> Result<
             continue;
         }
 
-        // Process inline vars.
-        let command = process_vars(command, &env, args);
+        let command = crate::expand::expand_words(command, args);
         if command.is_empty() {
             continue;
         }
 
+        if crate::options::is_set("xtrace") {
+            trace(&env, &command);
+        }
+
         cmd = Some(command[0].clone());
         let args = &command[1..];
         match cmd.as_ref().unwrap().as_str() {
             "cd" => {
-                if args.len() != 1 {
-                    println!("cd: must have a single argument.");
-                    prev_child = None;
-                    continue;
-                }
-                let new_dir = args[0].as_str();
-                let root = Path::new(new_dir);
-                if let Err(e) = std::env::set_current_dir(root) {
-                    println!("{e}");
-                }
-
                 prev_child = None;
+                crate::dirs::cd(args)?;
+            }
+            "pushd" => {
+                prev_child = None;
+                crate::dirs::pushd(args)?;
+            }
+            "popd" => {
+                prev_child = None;
+                crate::dirs::popd(args)?;
+            }
+            "dirs" => {
+                prev_child = None;
+                crate::dirs::dirs(args)?;
+            }
+            "export" => {
+                prev_child = None;
+                crate::vars::export(args)?;
+            }
+            "readonly" => {
+                prev_child = None;
+                crate::vars::readonly(args)?;
+            }
+            "set" => {
+                prev_child = None;
+                crate::options::set(args)?;
+            }
+            "unset" => {
+                prev_child = None;
+                crate::vars::unset(args)?;
+            }
+            "env" if args.is_empty() => {
+                prev_child = None;
+                crate::vars::env()?;
+            }
+            "alias" => {
+                prev_child = None;
+                crate::alias::alias(args)?;
+            }
+            "unalias" => {
+                prev_child = None;
+                crate::alias::unalias(args)?;
+            }
+            "complete" => {
+                prev_child = None;
+                crate::complete::complete_builtin(args)?;
+            }
+            "compgen" => {
+                prev_child = None;
+                crate::complete::compgen(args)?;
+            }
+            "bind" => {
+                prev_child = None;
+                crate::bind::bind(args)?;
+            }
+            "history" => {
+                prev_child = None;
+                crate::term::history(args)?;
             }
             "quit" => crate::exit(0),
             "exit" => process_exit(args),
@@ -152,7 +184,9 @@ pub fn run(commands: Vec<Vec<String>>, global: bool, args: &[String]) -> Result<
                     .stdin(stdin)
                     .stdout(stdout)
                     .stderr(stderr)
-                    .envs(env.into_iter())
+                    .env_clear()
+                    .envs(crate::vars::exported())
+                    .envs(env)
                     .spawn();
 
                 match child {
@@ -203,7 +237,9 @@ pub fn run(commands: Vec<Vec<String>>, global: bool, args: &[String]) -> Result<
     }
 }
 
-pub fn run_script(fname: &str, args: Vec<String>, global: bool) {
+/// Run the commands in `fname`. A script exits on the first failing command;
+/// an init script (`rc`, see '-i') runs on, as commands typed in would.
+pub fn run_script(fname: &str, args: Vec<String>, global: bool, rc: bool) {
     let script = {
         match std::fs::read_to_string(std::path::Path::new(fname)) {
             Ok(text) => text,
@@ -221,20 +257,68 @@ pub fn run_script(fname: &str, args: Vec<String>, global: bool) {
             continue;
         }
         if let Some(commands) = parser.parse_line(line) {
-            if let Err(err) = run(commands, global, &args) {
-                std::process::exit(err);
-            }
+            let started = std::time::Instant::now();
+            let status = match run(commands, global, &args) {
+                Ok(()) => 0,
+                Err(err) if !rc => std::process::exit(err),
+                Err(err) => err,
+            };
+            set_last_status(status, started.elapsed());
         }
     }
 }
 
 pub fn run_command(args: Vec<String>) {
-    if let Err(err) = run(vec![args], true, &[]) {
+    // The arguments are already split into words: quote them so that
+    // expansion leaves them as they are.
+    let command = args.iter().map(|arg| crate::expand::quote(arg)).collect();
+    if let Err(err) = run(vec![command], true, &[]) {
         std::process::exit(err);
     }
     std::process::exit(0);
 }
 
+// 'set -x': print the command about to run, after $PS4.
+fn trace(env: &[(&str, String)], command: &[String]) {
+    let ps4 = crate::vars::get("PS4").unwrap_or_else(|| "+ ".to_owned());
+    let words: Vec<String> = env
+        .iter()
+        .map(|(k, v)| format!("{k}={}", crate::expand::quote(v)))
+        .chain(command.iter().map(|word| crate::expand::quote(word)))
+        .collect();
+    eprintln!("{}{}", crate::prompt::expand(ps4.as_str()), words.join(" "));
+}
+
+/// Run a simple command and return what it prints, without trailing
+/// newlines: command substitution, e.g. in prompts.
+pub fn output(line: &str) -> String {
+    let mut parser = crate::line_parser::LineParser::new();
+    let Some(commands) = parser.parse_line(line) else {
+        return String::new();
+    };
+    let [command] = commands.as_slice() else {
+        return String::new(); // No pipes (yet).
+    };
+    let command = crate::expand::expand_words(command, &[]);
+    let Some((cmd, args)) = command.split_first() else {
+        return String::new();
+    };
+
+    match std::process::Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .env_clear()
+        .envs(crate::vars::exported())
+        .output()
+    {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .trim_end_matches('\n')
+            .to_owned(),
+        Err(_) => String::new(),
+    }
+}
+
 fn process_exit(args: &[String]) -> ! {
     if args.is_empty() {
         crate::exit(0);
@@ -0,0 +1,621 @@
+// Word expansion.
+//
+// Words come from LineParser with their quotes intact and are expanded
+// here in the POSIX order: brace expansion, tilde expansion, parameter
+// expansion ($NAME, ${NAME}, $1, $#, $@), field splitting, pathname
+// expansion, and finally quote removal.
+
+// Which chars of a word are "active", i.e. not quoted, not escaped,
+// and not inside a ${...} parameter.
+fn active_chars(chars: &[char]) -> Vec<bool> {
+    let mut result = vec![false; chars.len()];
+    let mut quote: Option<char> = None;
+    let mut idx = 0;
+    while idx < chars.len() {
+        let c = chars[idx];
+        if c == '\\' && quote != Some('\'') {
+            idx += 2;
+            continue;
+        }
+        match quote {
+            Some(q) => {
+                if c == q {
+                    quote = None;
+                }
+            }
+            None => {
+                if c == '\'' || c == '"' {
+                    quote = Some(c);
+                } else if c == '$' && chars.get(idx + 1) == Some(&'{') {
+                    while idx < chars.len() && chars[idx] != '}' {
+                        idx += 1;
+                    }
+                } else {
+                    result[idx] = true;
+                }
+            }
+        }
+        idx += 1;
+    }
+    result
+}
+
+// Find the '}' matching the '{' at `open`, and top-level commas in between.
+fn find_brace_close(chars: &[char], active: &[bool], open: usize) -> Option<(usize, Vec<usize>)> {
+    let mut depth = 0;
+    let mut commas = vec![];
+    for idx in (open + 1)..chars.len() {
+        if !active[idx] {
+            continue;
+        }
+        match chars[idx] {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some((idx, commas)),
+            '}' => depth -= 1,
+            ',' if depth == 0 => commas.push(idx),
+            _ => {}
+        }
+    }
+    None
+}
+
+// {1..10}, {01..10..3}, {a..e}, {z..a..2}.
+fn expand_sequence(inner: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = inner.split("..").collect();
+    if parts.len() != 2 && parts.len() != 3 {
+        return None;
+    }
+
+    let step = match parts.get(2) {
+        Some(step) => step.parse::<i64>().ok()?.unsigned_abs().max(1),
+        None => 1,
+    };
+
+    let mut result = vec![];
+    if let (Ok(start), Ok(end)) = (parts[0].parse::<i64>(), parts[1].parse::<i64>()) {
+        // Zero-pad if either end has a leading zero.
+        let padded = |s: &str| {
+            let digits = s.trim_start_matches('-');
+            digits.len() > 1 && digits.starts_with('0')
+        };
+        let width = if padded(parts[0]) || padded(parts[1]) {
+            parts[0].len().max(parts[1].len())
+        } else {
+            0
+        };
+
+        let mut num = start;
+        loop {
+            result.push(format!("{num:0width$}"));
+            let next = if start <= end {
+                num.checked_add_unsigned(step).filter(|next| *next <= end)
+            } else {
+                num.checked_sub_unsigned(step).filter(|next| *next >= end)
+            };
+            match next {
+                Some(next) => num = next,
+                None => break,
+            }
+        }
+        return Some(result);
+    }
+
+    let single_letter = |s: &str| {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => Some(c as u8),
+            _ => None,
+        }
+    };
+    let (start, end) = (single_letter(parts[0])?, single_letter(parts[1])?);
+    let mut c = start;
+    loop {
+        result.push((c as char).to_string());
+        if (start <= end && c as u64 + step > end as u64)
+            || (start > end && (c as u64) < end as u64 + step)
+        {
+            break;
+        }
+        if start <= end {
+            c += step as u8;
+        } else {
+            c -= step as u8;
+        }
+    }
+    Some(result)
+}
+
+/// Brace expansion: "a{b,c}d" becomes ["abd", "acd"], "{1..3}" becomes ["1", "2", "3"].
+fn expand_braces(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    let active = active_chars(&chars);
+
+    for open in 0..chars.len() {
+        if !active[open] || chars[open] != '{' {
+            continue;
+        }
+        let Some((close, commas)) = find_brace_close(&chars, &active, open) else {
+            continue;
+        };
+
+        let items: Vec<String> = if commas.is_empty() {
+            let inner: String = chars[(open + 1)..close].iter().collect();
+            match expand_sequence(inner.as_str()) {
+                Some(items) => items,
+                None => continue, // Not a brace expression: "{a}".
+            }
+        } else {
+            let mut bounds = vec![open];
+            bounds.extend(commas);
+            bounds.push(close);
+            bounds
+                .windows(2)
+                .map(|w| chars[(w[0] + 1)..w[1]].iter().collect())
+                .collect()
+        };
+
+        let preamble: String = chars[..open].iter().collect();
+        let postscript: String = chars[(close + 1)..].iter().collect();
+        let mut result = vec![];
+        for item in items {
+            result.append(&mut expand_braces(
+                format!("{preamble}{item}{postscript}").as_str(),
+            ));
+        }
+        return result;
+    }
+
+    vec![word.to_owned()]
+}
+
+#[cfg(unix)]
+fn user_home(user: &str) -> Option<String> {
+    let name = std::ffi::CString::new(user).ok()?;
+    let mut passwd: libc::passwd = unsafe { core::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::passwd = core::ptr::null_mut();
+    let err = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if err != 0 || result.is_null() || passwd.pw_dir.is_null() {
+        return None;
+    }
+    let dir = unsafe { std::ffi::CStr::from_ptr(passwd.pw_dir) };
+    Some(dir.to_string_lossy().into_owned())
+}
+
+// There is no password database: only the current user's home is known.
+#[cfg(not(unix))]
+fn user_home(user: &str) -> Option<String> {
+    let current = crate::vars::get("USER").or_else(|| crate::vars::get("USERNAME"));
+    if current?.as_str() == user {
+        crate::vars::get("HOME")
+    } else {
+        None
+    }
+}
+
+// If `chars` start with a tilde-prefix ("~", "~user", "~+", "~-"), return
+// its expansion and length. A prefix with any quoted chars is not expanded.
+// In assignments the prefix also ends at ':', as in "PATH=~/bin:~:/bin".
+fn expand_tilde(chars: &[char], assignment: bool) -> Option<(String, usize)> {
+    debug_assert_eq!(chars[0], '~');
+    let len = chars
+        .iter()
+        .position(|c| *c == '/' || (assignment && *c == ':'))
+        .unwrap_or(chars.len());
+    let prefix: String = chars[1..len].iter().collect();
+    if prefix.contains(['\\', '\'', '"', '$']) {
+        return None;
+    }
+
+    let expanded = match prefix.as_str() {
+        "" => crate::vars::get("HOME"),
+        "+" => crate::vars::get("PWD"),
+        "-" => crate::vars::get("OLDPWD"),
+        user => user_home(user),
+    }?;
+    Some((expanded, len))
+}
+
+fn parameter_value(name: &str, args: &[String]) -> String {
+    if name == "#" {
+        return args.len().saturating_sub(1).to_string();
+    }
+    if name == "?" {
+        return crate::exec::last_status().to_string();
+    }
+    if let Ok(idx) = name.parse::<usize>() {
+        return args.get(idx).cloned().unwrap_or_default();
+    }
+    crate::vars::get(name).unwrap_or_default()
+}
+
+// If `chars` start with "$NAME", "${NAME}", "$1", "$#" or "$?", return the
+// parameter's value and the length of the expression.
+pub fn expand_parameter(chars: &[char], args: &[String]) -> Option<(String, usize)> {
+    debug_assert_eq!(chars[0], '$');
+    let next = *chars.get(1)?;
+
+    if next == '{' {
+        let close = chars.iter().position(|c| *c == '}')?;
+        let name: String = chars[2..close].iter().collect();
+        let positional = !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit());
+        if !(crate::vars::is_name(name.as_str()) || positional || name == "#" || name == "?") {
+            return None;
+        }
+        return Some((parameter_value(name.as_str(), args), close + 1));
+    }
+
+    if next.is_ascii_digit() || next == '#' || next == '?' {
+        return Some((parameter_value(next.to_string().as_str(), args), 2));
+    }
+
+    if next.is_ascii_alphabetic() || next == '_' {
+        let len = chars[1..]
+            .iter()
+            .position(|c| !(c.is_ascii_alphanumeric() || *c == '_'))
+            .map_or(chars.len(), |pos| pos + 1);
+        let name: String = chars[1..len].iter().collect();
+        return Some((parameter_value(name.as_str(), args), len));
+    }
+
+    None
+}
+
+// A word after tilde and parameter expansion, before field splitting.
+#[derive(Clone, Copy)]
+enum Piece {
+    Char(char),
+    Expanded(char), // A char from an unquoted expansion: subject to field splitting.
+    Quoted,         // Quotes: the field exists even if it is empty ("").
+    FieldBreak,     // Between positional parameters in $@.
+}
+
+// Tilde and parameter expansion plus quote removal.
+fn expand_word(word: &str, args: &[String], assignment: bool) -> Vec<Piece> {
+    let params = args.get(1..).unwrap_or(&[]);
+    if params.is_empty() && (word == "$@" || word == "\"$@\"") {
+        return vec![];
+    }
+
+    let chars: Vec<char> = word.chars().collect();
+    let mut result = vec![];
+    let mut quote: Option<char> = None;
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        let c = chars[idx];
+
+        // Tilde-prefixes start words and, in assignments, follow ':'.
+        if c == '~' && quote.is_none() && (idx == 0 || (assignment && chars[idx - 1] == ':')) {
+            if let Some((expanded, len)) = expand_tilde(&chars[idx..], assignment) {
+                // Not subject to field splitting.
+                result.push(Piece::Quoted);
+                result.extend(expanded.chars().map(Piece::Char));
+                idx += len;
+                continue;
+            }
+        }
+
+        let to_piece = |c| {
+            if quote.is_none() {
+                Piece::Expanded(c)
+            } else {
+                Piece::Char(c)
+            }
+        };
+
+        match c {
+            // Backslashes are literal in single quotes; in double quotes
+            // they only escape the chars that are special there.
+            '\\' if quote == Some('\'') => result.push(Piece::Char(c)),
+            '\\' if quote == Some('"')
+                && !matches!(chars.get(idx + 1), Some('$' | '`' | '"' | '\\' | '\n')) =>
+            {
+                result.push(Piece::Char(c))
+            }
+            '\\' => {
+                match chars.get(idx + 1) {
+                    Some('\n') => {} // A line continuation.
+                    Some(next) => result.push(Piece::Char(*next)),
+                    None => {}
+                }
+                idx += 2;
+                continue;
+            }
+            '\'' | '"' if quote.is_none() => {
+                quote = Some(c);
+                result.push(Piece::Quoted);
+            }
+            c if Some(c) == quote => quote = None,
+            '$' if quote != Some('\'') && chars.get(idx + 1) == Some(&'@') => {
+                // Each positional parameter is a separate field.
+                for (param_idx, param) in params.iter().enumerate() {
+                    if param_idx > 0 {
+                        result.push(Piece::FieldBreak);
+                    }
+                    if quote.is_some() {
+                        result.push(Piece::Quoted);
+                    }
+                    result.extend(param.chars().map(to_piece));
+                }
+                idx += 2;
+                continue;
+            }
+            '$' if quote != Some('\'') => {
+                if let Some((value, len)) = expand_parameter(&chars[idx..], args) {
+                    result.extend(value.chars().map(to_piece));
+                    idx += len;
+                    continue;
+                }
+                result.push(Piece::Char(c));
+            }
+            c => result.push(Piece::Char(c)),
+        }
+        idx += 1;
+    }
+
+    result
+}
+
+// Field splitting: split the results of unquoted expansions on IFS.
+//
+// IFS whitespace (space, tab, newline) around fields is dropped, and
+// runs of it separate fields; each other IFS char separates fields,
+// so "a::b" with IFS=':' has an empty field in the middle.
+fn split_fields(pieces: &[Piece]) -> Vec<String> {
+    // An unset IFS means the default; an empty one disables splitting.
+    let ifs = crate::vars::get("IFS").unwrap_or_else(|| " \t\n".to_owned());
+    let is_delimiter = |piece: Option<&Piece>| match piece {
+        Some(Piece::Expanded(c)) => ifs.contains(*c),
+        _ => false,
+    };
+    let is_whitespace = |piece: Option<&Piece>| match piece {
+        Some(Piece::Expanded(c)) => ifs.contains(*c) && matches!(*c, ' ' | '\t' | '\n'),
+        _ => false,
+    };
+
+    let mut fields = vec![];
+    let mut current = String::new();
+    let mut has_field = false;
+    let mut idx = 0;
+
+    while is_whitespace(pieces.get(idx)) {
+        idx += 1;
+    }
+
+    while idx < pieces.len() {
+        if is_delimiter(pieces.get(idx)) {
+            // Whitespace, at most one other IFS char, more whitespace.
+            let mut other_seen = false;
+            while is_delimiter(pieces.get(idx)) {
+                if !is_whitespace(pieces.get(idx)) {
+                    if other_seen {
+                        break;
+                    }
+                    other_seen = true;
+                }
+                idx += 1;
+            }
+
+            if has_field || other_seen {
+                fields.push(std::mem::take(&mut current));
+                has_field = false;
+            }
+            continue;
+        }
+
+        match pieces[idx] {
+            Piece::Char(c) | Piece::Expanded(c) => {
+                current.push(c);
+                has_field = true;
+            }
+            Piece::Quoted => has_field = true,
+            Piece::FieldBreak => {
+                fields.push(std::mem::take(&mut current));
+                has_field = false;
+            }
+        }
+        idx += 1;
+    }
+
+    if has_field {
+        fields.push(current);
+    }
+    fields
+}
+
+fn expand_pathname(field: String) -> Vec<String> {
+    // Not done (yet): glob processing removed trailing slashes, which
+    // are meaningful in commands like mv.
+    vec![field]
+}
+
+/// Expand the words of a command into its arguments.
+pub fn expand_words(words: &[String], args: &[String]) -> Vec<String> {
+    let mut result = vec![];
+    for word in words {
+        for word in expand_braces(word.as_str()) {
+            for field in split_fields(&expand_word(word.as_str(), args, false)) {
+                result.append(&mut expand_pathname(field));
+            }
+        }
+    }
+    result
+}
+
+/// Expand the value in `NAME=value`: no brace expansion or field splitting,
+/// and tilde-prefixes are recognized after each ':' as well.
+pub fn expand_assignment(value: &str, args: &[String]) -> String {
+    expand_word(value, args, true)
+        .iter()
+        .filter_map(|piece| match piece {
+            Piece::Char(c) | Piece::Expanded(c) => Some(*c),
+            Piece::FieldBreak => Some(' '),
+            Piece::Quoted => None,
+        })
+        .collect()
+}
+
+/// Quote `s` so that it expands back to itself.
+pub fn quote(s: &str) -> String {
+    const SPECIAL: &[char] = &[
+        ' ', '\t', '\n', '\\', '\'', '"', '|', '$', '~', '{', '}', '*', '?', '[', ']', '>', '<',
+        '&', ';', '(', ')', '#', '`',
+    ];
+    if !s.is_empty() && !s.contains(SPECIAL) {
+        return s.to_owned();
+    }
+
+    // Nothing is special in single quotes, but a single quote ends them.
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brace_lists() {
+        assert_eq!(expand_braces("a{b,c}d"), ["abd", "acd"]);
+        assert_eq!(expand_braces("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand_braces("x{a,{b,c}}"), ["xa", "xb", "xc"]);
+        assert_eq!(expand_braces("{,a}"), ["", "a"]);
+    }
+
+    #[test]
+    fn brace_non_expressions() {
+        assert_eq!(expand_braces("{a}"), ["{a}"]);
+        assert_eq!(expand_braces("{a,b"), ["{a,b"]);
+        assert_eq!(expand_braces("\\{a,b}"), ["\\{a,b}"]);
+        assert_eq!(expand_braces("'{a,b}'"), ["'{a,b}'"]);
+        assert_eq!(expand_braces("\"{a,b}\""), ["\"{a,b}\""]);
+        assert_eq!(expand_braces("{a\\,b}"), ["{a\\,b}"]);
+        assert_eq!(expand_braces("${a,b}"), ["${a,b}"]);
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(expand_braces("{1..3}"), ["1", "2", "3"]);
+        assert_eq!(expand_braces("{3..1}"), ["3", "2", "1"]);
+        assert_eq!(expand_braces("{-1..1}"), ["-1", "0", "1"]);
+        assert_eq!(expand_braces("{01..10..3}"), ["01", "04", "07", "10"]);
+        assert_eq!(expand_braces("{1..10..-4}"), ["1", "5", "9"]);
+        assert_eq!(expand_braces("{a..e..2}"), ["a", "c", "e"]);
+        assert_eq!(expand_braces("{z..x}"), ["z", "y", "x"]);
+        assert_eq!(expand_braces("{1..a}"), ["{1..a}"]);
+        assert_eq!(expand_braces("{1..2..3..4}"), ["{1..2..3..4}"]);
+    }
+
+    #[test]
+    fn sequence_overflow() {
+        assert_eq!(
+            expand_braces("{1..9223372036854775807..9223372036854775807}"),
+            ["1"]
+        );
+        assert_eq!(
+            expand_braces("{9223372036854775806..9223372036854775807}"),
+            ["9223372036854775806", "9223372036854775807"]
+        );
+        assert_eq!(
+            expand_braces("{-9223372036854775807..-9223372036854775808}"),
+            ["-9223372036854775807", "-9223372036854775808"]
+        );
+        assert_eq!(expand_braces("{0..1..-9223372036854775808}"), ["0"]);
+        assert_eq!(expand_braces("{a..z..9223372036854775807}"), ["a"]);
+    }
+
+    #[test]
+    fn tilde() {
+        crate::vars::set("HOME", "/home/u").unwrap();
+        let expand = |word: &str, assignment: bool| -> String {
+            expand_word(word, &[], assignment)
+                .iter()
+                .filter_map(|piece| match piece {
+                    Piece::Char(c) | Piece::Expanded(c) => Some(*c),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(expand("~", false), "/home/u");
+        assert_eq!(expand("~/bin", false), "/home/u/bin");
+        assert_eq!(expand("a~", false), "a~");
+        assert_eq!(expand("'~'", false), "~");
+        assert_eq!(expand("~:~", false), "~:~");
+        assert_eq!(expand("~:~", true), "/home/u:/home/u");
+        assert_eq!(expand("~/bin:~:/bin", true), "/home/u/bin:/home/u:/bin");
+    }
+
+    #[test]
+    fn field_splitting() {
+        let split = |ifs: &str, word: &str| -> Vec<String> {
+            crate::vars::set("IFS", ifs).unwrap();
+            expand_words(&[word.to_owned()], &[])
+        };
+
+        crate::vars::set("SPLIT", "  a b\t\tc  ").unwrap();
+        assert_eq!(split(" \t\n", "$SPLIT"), ["a", "b", "c"]);
+        assert_eq!(split(" \t\n", "\"$SPLIT\""), ["  a b\t\tc  "]);
+        assert_eq!(split(" \t\n", "x${SPLIT}y"), ["x", "a", "b", "c", "y"]);
+        assert_eq!(split("", "$SPLIT"), ["  a b\t\tc  "]);
+
+        // Other IFS chars each separate fields; whitespace around them is dropped.
+        crate::vars::set("SPLIT", "a::b : c:").unwrap();
+        assert_eq!(split(":", "$SPLIT"), ["a", "", "b ", " c"]);
+        assert_eq!(split(" :", "$SPLIT"), ["a", "", "b", "c"]);
+
+        // Only the results of unquoted expansions are split.
+        assert_eq!(split(":", "a:b$SPLIT"), ["a:ba", "", "b ", " c"]);
+
+        // Empty expansions disappear unless quoted.
+        crate::vars::set("SPLIT", "").unwrap();
+        assert_eq!(split(" \t\n", "$SPLIT"), Vec::<String>::new());
+        assert_eq!(split(" \t\n", "\"$SPLIT\""), [""]);
+        assert_eq!(split(" \t\n", "''$SPLIT"), [""]);
+
+        // "$@" keeps the positional parameters as separate fields.
+        let args = ["rush", "a b", "c"].map(String::from);
+        crate::vars::set("IFS", " \t\n").unwrap();
+        assert_eq!(expand_words(&["\"$@\"".to_owned()], &args), ["a b", "c"]);
+        assert_eq!(expand_words(&["$@".to_owned()], &args), ["a", "b", "c"]);
+        assert_eq!(
+            expand_words(&["\"$@\"".to_owned()], &args[..1]),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn backslashes() {
+        let expand = |word: &str| expand_words(&[word.to_owned()], &[]);
+        crate::vars::set("BACKSLASH", "v").unwrap();
+
+        // Literal in single quotes.
+        assert_eq!(expand(r"'\u@\h \$'"), [r"\u@\h \$"]);
+        assert_eq!(expand(r"'a\'"), [r"a\"]);
+        assert_eq!(expand(r"'\\'"), [r"\\"]);
+
+        // In double quotes, only before $ ` " \ and newline.
+        assert_eq!(expand(r#""a\b""#), [r"a\b"]);
+        assert_eq!(expand(r#""\$BACKSLASH \"\\\`""#), [r#"$BACKSLASH "\`"#]);
+        assert_eq!(expand("\"a\\\nb\""), ["ab"]);
+
+        // Unquoted, before any char.
+        assert_eq!(expand(r"\u\$BACKSLASH\\"), [r"u$BACKSLASH\"]);
+        assert_eq!(expand(r"\'$BACKSLASH"), ["'v"]);
+    }
+
+    #[test]
+    fn quoting() {
+        for s in ["", "a b", "it's", r"a\b", "$HOME", r"'\''"] {
+            assert_eq!(expand_words(&[quote(s)], &[]), [s], "{}", quote(s));
+        }
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+}
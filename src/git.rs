@@ -0,0 +1,134 @@
+// The git branch for the prompt (see '\g' in crate::prompt).
+//
+// There may be no git binary: read .git directly. Where the repository is
+// is cached per directory; HEAD is read again only when it changes.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[derive(Clone)]
+struct Repo {
+    git_dir: PathBuf,    // Where HEAD is.
+    common_dir: PathBuf, // Where refs are: differs from git_dir in worktrees.
+    head_modified: Option<SystemTime>,
+    head: Option<String>,
+}
+
+// By the current directory; None if it is not in a repository.
+static REPOS: Mutex<BTreeMap<PathBuf, Option<Repo>>> = Mutex::new(BTreeMap::new());
+
+/// The current branch, or, for a detached HEAD, the tag it points at or
+/// its short hash. None outside of git repositories.
+pub fn head() -> Option<String> {
+    let cwd = std::env::current_dir().ok()?;
+    let mut repos = REPOS.lock().unwrap();
+    let repo = match repos.get(&cwd) {
+        // A repository may have been created since.
+        Some(None) if !cwd.join(".git").exists() => return None,
+        Some(Some(repo)) => Some(repo.clone()),
+        _ => find_repo(&cwd),
+    };
+    let Some(mut repo) = repo else {
+        repos.insert(cwd, None);
+        return None;
+    };
+
+    let head_path = repo.git_dir.join("HEAD");
+    let modified = std::fs::metadata(&head_path)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    if repo.head.is_none() || modified != repo.head_modified {
+        repo.head_modified = modified;
+        repo.head = read_head(&head_path, &repo.common_dir);
+    }
+    let head = repo.head.clone();
+    repos.insert(cwd, Some(repo));
+    head
+}
+
+// Look for .git in `dir` and up.
+fn find_repo(dir: &Path) -> Option<Repo> {
+    for dir in dir.ancestors() {
+        let dot_git = dir.join(".git");
+        let git_dir = if dot_git.is_dir() {
+            dot_git
+        } else if dot_git.is_file() {
+            // Worktrees and submodules: "gitdir: <path>".
+            let text = std::fs::read_to_string(&dot_git).ok()?;
+            let path = text.strip_prefix("gitdir:")?.trim();
+            dir.join(path)
+        } else {
+            continue;
+        };
+
+        let common_dir = match std::fs::read_to_string(git_dir.join("commondir")) {
+            Ok(path) => git_dir.join(path.trim()),
+            Err(_) => git_dir.clone(),
+        };
+        return Some(Repo {
+            git_dir,
+            common_dir,
+            head_modified: None,
+            head: None,
+        });
+    }
+    None
+}
+
+fn read_head(head_path: &Path, common_dir: &Path) -> Option<String> {
+    let head = std::fs::read_to_string(head_path).ok()?;
+    let head = head.trim();
+    if let Some(reference) = head.strip_prefix("ref:") {
+        let reference = reference.trim();
+        return Some(
+            reference
+                .strip_prefix("refs/heads/")
+                .unwrap_or(reference)
+                .to_owned(),
+        );
+    }
+
+    // Detached.
+    if head.len() < 7 || !head.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(find_tag(common_dir, head).unwrap_or_else(|| head[..7].to_owned()))
+}
+
+// A tag pointing at commit `hash`, from refs/tags or packed-refs.
+fn find_tag(common_dir: &Path, hash: &str) -> Option<String> {
+    // Loose tags: only lightweight ones, as annotated tags point at tag
+    // objects, which would have to be inflated.
+    if let Ok(entries) = std::fs::read_dir(common_dir.join("refs/tags")) {
+        for entry in entries.flatten() {
+            if let Ok(value) = std::fs::read_to_string(entry.path()) {
+                if value.trim() == hash {
+                    return Some(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+    }
+
+    // "<hash> refs/tags/<name>" lines, each optionally followed by
+    // "^<hash>" with the commit an annotated tag points at.
+    let packed = std::fs::read_to_string(common_dir.join("packed-refs")).ok()?;
+    let mut prev_tag = None;
+    for line in packed.lines() {
+        if let Some(peeled) = line.strip_prefix('^') {
+            if peeled == hash && prev_tag.is_some() {
+                return prev_tag;
+            }
+            continue;
+        }
+        let Some((value, name)) = line.split_once(' ') else {
+            continue;
+        };
+        prev_tag = name.strip_prefix("refs/tags/").map(|tag| tag.to_owned());
+        if value == hash && prev_tag.is_some() {
+            return prev_tag;
+        }
+    }
+    None
+}
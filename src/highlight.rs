@@ -0,0 +1,161 @@
+// Syntax highlighting of the line being edited (see Term::redraw_line).
+//
+// Words come from line_parser::tokens(), so they are colored the way
+// they will be parsed. Colors are SGR parameters in $HIGHLIGHT_COLORS,
+// e.g. "command=1;32:unknown=4;31:comment=" (empty: not colored);
+// there is no highlighting if $NO_COLOR is set (https://no-color.org).
+
+use std::ops::Range;
+
+use crate::line_parser::Token;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Command,
+    UnknownCommand,
+    String,
+    Variable,
+    Operator,
+    Redirect,
+    Comment,
+}
+
+// Names in $HIGHLIGHT_COLORS and default colors, in the order of Kind.
+const KINDS: [(&str, &str); 7] = [
+    ("command", "32"),
+    ("unknown", "31"),
+    ("string", "33"),
+    ("variable", "35"),
+    ("operator", "36"),
+    ("redirect", "36"),
+    ("comment", "90"),
+];
+
+pub fn is_enabled() -> bool {
+    crate::vars::get("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+/// `line` with color escape sequences.
+pub fn highlight(line: &str) -> String {
+    let colors = colors();
+    let mut result = String::new();
+    let mut prev_end = 0;
+    for (range, kind) in spans(line) {
+        result.push_str(&line[prev_end..range.start]);
+        let color = colors[kind as usize].as_str();
+        if color.is_empty() {
+            result.push_str(&line[range.clone()]);
+        } else {
+            result.push_str(format!("\x1b[{color}m{}\x1b[0m", &line[range.clone()]).as_str());
+        }
+        prev_end = range.end;
+    }
+    result.push_str(&line[prev_end..]);
+    result
+}
+
+fn colors() -> [String; 7] {
+    let mut colors = KINDS.map(|(_, color)| color.to_owned());
+    let config = crate::vars::get("HIGHLIGHT_COLORS").unwrap_or_default();
+    for entry in config.split(':') {
+        let Some((name, color)) = entry.split_once('=') else {
+            continue;
+        };
+        if let Some(idx) = KINDS.iter().position(|(kind, _)| *kind == name) {
+            colors[idx] = color.to_owned();
+        }
+    }
+    colors
+}
+
+// What to color, in order.
+fn spans(line: &str) -> Vec<(Range<usize>, Kind)> {
+    let mut result = vec![];
+    let mut command_expected = true;
+    let mut prev_end = 0;
+    for (range, token) in crate::line_parser::tokens(line) {
+        // Each (pasted) line is a separate command, see main().
+        if line[prev_end..range.start].contains('\n') {
+            command_expected = true;
+        }
+        prev_end = range.end;
+        let word = &line[range.clone()];
+        match token {
+            Token::Pipe => {
+                result.push((range, Kind::Operator));
+                command_expected = true;
+            }
+            Token::Comment => result.push((range, Kind::Comment)),
+            // The file name is colored as any other word.
+            Token::Word if word == ">" || word == ">>" => result.push((range, Kind::Redirect)),
+            Token::Word if command_expected && !crate::line_parser::is_assignment(word) => {
+                let kind = if crate::complete::is_command(word) {
+                    Kind::Command
+                } else {
+                    Kind::UnknownCommand
+                };
+                result.push((range, kind));
+                command_expected = false;
+            }
+            Token::Word => word_spans(word, range.start, &mut result),
+        }
+    }
+    result
+}
+
+// Quoted strings and parameters in `word`, which starts at `offset`.
+fn word_spans(word: &str, offset: usize, result: &mut Vec<(Range<usize>, Kind)>) {
+    let mut push = |range: Range<usize>, kind| {
+        if !range.is_empty() {
+            result.push(((range.start + offset)..(range.end + offset), kind));
+        }
+    };
+
+    let chars: Vec<(usize, char)> = word.char_indices().collect();
+    let mut quote: Option<char> = None;
+    let mut quote_start = 0;
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        let (pos, c) = chars[idx];
+        match c {
+            '\\' if quote != Some('\'') => {
+                idx += 2;
+                continue;
+            }
+            '\'' | '"' if quote.is_none() => {
+                quote = Some(c);
+                quote_start = pos;
+            }
+            c if Some(c) == quote => {
+                push(quote_start..(pos + 1), Kind::String);
+                quote = None;
+            }
+            '$' if quote != Some('\'') => {
+                let rest: Vec<char> = chars[idx..].iter().map(|(_, c)| *c).collect();
+                let len = if rest.get(1) == Some(&'@') {
+                    Some(2)
+                } else {
+                    crate::expand::expand_parameter(&rest, &[]).map(|(_, len)| len)
+                };
+                if let Some(len) = len {
+                    let end = chars.get(idx + len).map_or(word.len(), |(pos, _)| *pos);
+                    if quote.is_some() {
+                        push(quote_start..pos, Kind::String);
+                        quote_start = end;
+                    }
+                    push(pos..end, Kind::Variable);
+                    idx += len;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        idx += 1;
+    }
+
+    if quote.is_some() {
+        // Not closed yet.
+        push(quote_start..word.len(), Kind::String);
+    }
+}
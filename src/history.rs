@@ -0,0 +1,504 @@
+// Command history persisted in $HISTFILE.
+//
+// Each accepted command is appended to the file as soon as it is entered,
+// so concurrent rush sessions interleave their commands rather than
+// overwriting each other's; the file is locked while it is being written.
+
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+
+const DEFAULT_HISTSIZE: usize = 1000;
+
+fn histfile() -> Option<String> {
+    match crate::vars::get("HISTFILE") {
+        Some(file) if file.is_empty() => None, // HISTFILE= disables saving history.
+        Some(file) => Some(file),
+        None => crate::vars::get("HOME").map(|home| format!("{home}/.rush_history")),
+    }
+}
+
+fn limit(var: &str) -> Option<usize> {
+    crate::vars::get(var).and_then(|val| val.parse::<usize>().ok())
+}
+
+// How many entries to keep in memory.
+fn histsize() -> usize {
+    limit("HISTSIZE").unwrap_or(DEFAULT_HISTSIZE)
+}
+
+// How many lines to keep in the file.
+fn histfilesize() -> usize {
+    limit("HISTFILESIZE").unwrap_or_else(histsize)
+}
+
+#[cfg(unix)]
+fn lock(file: &File) {
+    use std::os::fd::AsRawFd;
+    unsafe {
+        libc::flock(file.as_raw_fd(), libc::LOCK_EX);
+    }
+}
+
+// Closing the file unlocks it.
+#[cfg(not(unix))]
+fn lock(_file: &File) {}
+
+fn open_locked(fname: &str, create: bool) -> std::io::Result<File> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(create)
+        .truncate(false)
+        .open(fname)?;
+    lock(&file);
+    Ok(file)
+}
+
+fn read_entries(file: &mut File) -> Vec<Vec<u8>> {
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes).ok();
+    bytes
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| String::from_utf8_lossy(line).into_owned().into_bytes())
+        .collect()
+}
+
+fn write_entries(file: &mut File, entries: &[Vec<u8>]) -> std::io::Result<()> {
+    let skip = entries.len().saturating_sub(histfilesize());
+    let mut bytes = vec![];
+    for entry in &entries[skip..] {
+        bytes.extend_from_slice(entry);
+        bytes.push(b'\n');
+    }
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(&bytes)
+}
+
+/// Drop the oldest entries beyond HISTSIZE.
+pub fn trim(history: &mut Vec<Vec<u8>>) {
+    let excess = history.len().saturating_sub(histsize());
+    history.drain(..excess);
+}
+
+/// Load the history file, truncating it to HISTFILESIZE lines.
+pub fn load() -> Vec<Vec<u8>> {
+    let Some(fname) = histfile() else {
+        return vec![];
+    };
+    let Ok(mut file) = open_locked(fname.as_str(), false) else {
+        return vec![];
+    };
+
+    let mut entries = read_entries(&mut file);
+    if entries.len() > histfilesize() {
+        write_entries(&mut file, &entries).ok();
+    }
+    trim(&mut entries);
+    entries
+}
+
+/// Append an entry to the history file.
+pub fn append(entry: &[u8]) {
+    let Some(fname) = histfile() else {
+        return;
+    };
+    let Ok(mut file) = open_locked(fname.as_str(), true) else {
+        return;
+    };
+    file.seek(std::io::SeekFrom::End(0)).ok();
+    let mut bytes = Vec::from(entry);
+    bytes.push(b'\n');
+    file.write_all(&bytes).ok();
+}
+
+// The words of a history entry, for word designators; quotes are kept.
+fn entry_words(entry: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for c in entry.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quote != Some('\'') {
+            escaped = true;
+        } else if quote == Some(c) {
+            quote = None;
+        } else if quote.is_none() && (c == '\'' || c == '"') {
+            quote = Some(c);
+        } else if quote.is_none() && c.is_ascii_whitespace() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+// Parse a word designator ("0", "^", "$", "*", "n-m", "n*", "n-") at the start of 'chars'
+// and select the words from 'entry'; returns the words and the designator length.
+fn select_words(chars: &[char], entry: &str) -> Result<(String, usize), String> {
+    let words = entry_words(entry);
+    let last = words.len().saturating_sub(1);
+    let number = |pos: usize| -> (Option<usize>, usize) {
+        let len = chars[pos..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        let num: String = chars[pos..(pos + len)].iter().collect();
+        // Too large numbers are out of range, not missing.
+        let num = (len > 0).then(|| num.parse::<usize>().unwrap_or(usize::MAX));
+        (num, pos + len)
+    };
+
+    let (range, len) = match chars.first() {
+        Some('^') => ((1, 1), 1),
+        Some('$') => ((last, last), 1),
+        Some('*') => ((1, last), 1),
+        Some('-') => match number(1) {
+            (Some(to), len) => ((0, to), len),
+            (None, len) => ((0, last.saturating_sub(1)), len),
+        },
+        Some(c) if c.is_ascii_digit() => {
+            let (from, len) = number(0);
+            let from = from.unwrap_or(usize::MAX);
+            match chars.get(len) {
+                Some('*') => ((from, last), len + 1),
+                Some('-') => match number(len + 1) {
+                    (Some(to), len) => ((from, to), len),
+                    (None, len) => ((from, last.saturating_sub(1)), len),
+                },
+                _ => ((from, from), len),
+            }
+        }
+        _ => return Err("bad word specifier".to_owned()),
+    };
+
+    let (from, to) = range;
+    if from > to {
+        // "!*" and "n*" of a single-word command expand to nothing.
+        if chars[len - 1] == '*' && words.len() <= from {
+            return Ok((String::new(), len));
+        }
+        return Err("bad word specifier".to_owned());
+    }
+    if to >= words.len() {
+        return Err("bad word specifier".to_owned());
+    }
+    Ok((words[from..=to].join(" "), len))
+}
+
+// Expand the history reference at the start of 'chars' (which starts with '!').
+// Returns None if the '!' does not start a reference.
+fn expand_event(chars: &[char], history: &[Vec<u8>]) -> Result<Option<(String, usize)>, String> {
+    let entry = |idx: usize| String::from_utf8_lossy(&history[idx]).into_owned();
+    let text = |len: usize| chars[..len].iter().collect::<String>();
+    let not_found = |len: usize| format!("{}: event not found", text(len));
+    let find = |len: usize, matches: &dyn Fn(&str) -> bool| {
+        history
+            .iter()
+            .rposition(|line| matches(String::from_utf8_lossy(line).as_ref()))
+            .ok_or_else(|| not_found(len))
+    };
+
+    let len;
+    let event = match chars.get(1) {
+        None | Some(' ' | '\t' | '\n' | '=' | '(' | '"') => return Ok(None),
+        Some('!') => {
+            len = 2;
+            history.len().checked_sub(1).ok_or_else(|| not_found(len))?
+        }
+        // "!$", "!^", "!*": words of the previous command.
+        Some('$' | '^' | '*') => {
+            len = 1;
+            history.len().checked_sub(1).ok_or_else(|| not_found(2))?
+        }
+        Some('?') => {
+            let end = chars[2..].iter().position(|c| *c == '?');
+            let needle: String = match end {
+                Some(end) => chars[2..(end + 2)].iter().collect(),
+                None => chars[2..].iter().collect(),
+            };
+            len = end.map_or(chars.len(), |end| end + 3);
+            find(len, &|line| line.contains(needle.as_str()))?
+        }
+        Some(c) if c.is_ascii_digit() || *c == '-' => {
+            let digits = chars[2..].iter().take_while(|c| c.is_ascii_digit()).count();
+            len = 2 + digits;
+            if *c == '-' && digits == 0 {
+                return Err(not_found(len));
+            }
+            let Ok(num) = text(len)[1..].trim_start_matches('-').parse::<usize>() else {
+                return Err(not_found(len));
+            };
+            let idx = if *c == '-' {
+                history.len().checked_sub(num)
+            } else {
+                num.checked_sub(1)
+            };
+            match idx {
+                Some(idx) if idx < history.len() => idx,
+                _ => return Err(not_found(len)),
+            }
+        }
+        Some(_) => {
+            len = 1 + chars[1..]
+                .iter()
+                .take_while(|c| !c.is_ascii_whitespace() && !matches!(c, ':' | ';' | '|' | '&'))
+                .count();
+            let prefix: String = chars[1..len].iter().collect();
+            find(len, &|line| line.starts_with(prefix.as_str()))?
+        }
+    };
+
+    let designator = match chars.get(len) {
+        Some(':') => Some(len + 1),
+        Some('$' | '^' | '*') if len == 1 => Some(len),
+        _ => None,
+    };
+    match designator {
+        None => Ok(Some((entry(event), len))),
+        Some(start) => {
+            let (words, designator_len) = select_words(&chars[start..], entry(event).as_str())
+                .map_err(|err| format!("{}: {err}", chars.iter().collect::<String>()))?;
+            Ok(Some((words, start + designator_len)))
+        }
+    }
+}
+
+/// History expansion: "!!", "!n", "!-n", "!prefix", "!?string?", optionally followed by
+/// a word designator (":n", ":^", ":$", ":*", ":n-m"), the shorthands "!$", "!^", "!*",
+/// and "^old^new" at the start of the line. References in single quotes or after
+/// a backslash are not expanded. Returns Ok(None) if there was nothing to expand.
+pub fn expand(line: &str, history: &[Vec<u8>]) -> Result<Option<String>, String> {
+    if let Some(rest) = line.strip_prefix('^') {
+        let Some((old, new)) = rest.split_once('^') else {
+            return Err(format!("{line}: substitution failed"));
+        };
+        let new = new.strip_suffix('^').unwrap_or(new);
+        let prev = match history.last() {
+            Some(prev) => String::from_utf8_lossy(prev).into_owned(),
+            None => return Err(format!("{line}: event not found")),
+        };
+        if old.is_empty() || !prev.contains(old) {
+            return Err(format!("{line}: substitution failed"));
+        }
+        return Ok(Some(prev.replacen(old, new, 1)));
+    }
+
+    let chars: Vec<char> = line.chars().collect();
+    let mut result = String::new();
+    let mut expanded = false;
+    let mut quote = None;
+    let mut idx = 0;
+    while idx < chars.len() {
+        let c = chars[idx];
+        match c {
+            '\\' if quote != Some('\'') => {
+                result.extend(&chars[idx..(idx + 2).min(chars.len())]);
+                idx += 2;
+                continue;
+            }
+            '\'' | '"' if quote.is_none() => quote = Some(c),
+            '\'' | '"' if quote == Some(c) => quote = None,
+            '!' if quote != Some('\'') => {
+                // In double quotes, a closing quote ends the reference.
+                let end = match quote {
+                    Some(_) => chars[idx..]
+                        .iter()
+                        .position(|c| *c == '"')
+                        .map_or(chars.len(), |pos| idx + pos),
+                    None => chars.len(),
+                };
+                if let Some((text, len)) = expand_event(&chars[idx..end], history)? {
+                    result.push_str(text.as_str());
+                    expanded = true;
+                    idx += len;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        result.push(c);
+        idx += 1;
+    }
+
+    Ok(if expanded { Some(result) } else { None })
+}
+
+fn print(history: &[Vec<u8>], count: usize) {
+    let mut stdout = std::io::stdout().lock();
+    let skip = history.len().saturating_sub(count);
+    for (idx, entry) in history.iter().enumerate().skip(skip) {
+        stdout
+            .write_all(format!("{:5}  ", idx + 1).as_bytes())
+            .unwrap();
+        stdout.write_all(entry).unwrap();
+        stdout.write_all(b"\n").unwrap();
+    }
+    stdout.flush().unwrap();
+}
+
+/// history [N] | -c | -d N | -w [file] | -r [file]
+pub fn builtin(history: &mut Vec<Vec<u8>>, args: &[String]) -> Result<(), i32> {
+    let file_arg = |fname: Option<&String>| match fname {
+        Some(fname) => Ok(fname.clone()),
+        None => histfile().ok_or_else(|| {
+            eprintln!("history: HISTFILE is not set.");
+            1
+        }),
+    };
+
+    match args.first().map(|arg| arg.as_str()) {
+        None => print(history, history.len()),
+        Some("-c") => history.clear(),
+        Some("-d") => {
+            // 'history -d N' deletes entry N; 'history -d -N' counts from the end.
+            let arg = args.get(1).map_or("", |arg| arg.as_str());
+            let idx = match arg.parse::<i64>() {
+                Ok(num) if num > 0 && (num as usize) <= history.len() => num as usize - 1,
+                Ok(num) if num < 0 && (num.unsigned_abs() as usize) <= history.len() => {
+                    history.len() - num.unsigned_abs() as usize
+                }
+                _ => {
+                    eprintln!("history: {arg}: history position out of range.");
+                    return Err(1);
+                }
+            };
+            history.remove(idx);
+        }
+        Some("-w") => {
+            let fname = file_arg(args.get(1))?;
+            let result = open_locked(fname.as_str(), true)
+                .and_then(|mut file| write_entries(&mut file, history.as_slice()));
+            if let Err(err) = result {
+                eprintln!("history: {fname}: {err}.");
+                return Err(1);
+            }
+        }
+        Some("-r") => {
+            let fname = file_arg(args.get(1))?;
+            match open_locked(fname.as_str(), false) {
+                Ok(mut file) => {
+                    history.append(&mut read_entries(&mut file));
+                    trim(history);
+                }
+                Err(err) => {
+                    eprintln!("history: {fname}: {err}.");
+                    return Err(1);
+                }
+            }
+        }
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(count) => print(history, count),
+            Err(_) => {
+                eprintln!("history: {arg}: invalid option.");
+                eprintln!("history: usage: history [N] | -c | -d N | -w [file] | -r [file]");
+                return Err(2);
+            }
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(entries: &[&str]) -> Vec<Vec<u8>> {
+        entries
+            .iter()
+            .map(|entry| entry.as_bytes().to_vec())
+            .collect()
+    }
+
+    fn expanded(line: &str, entries: &[&str]) -> Result<Option<String>, String> {
+        expand(line, &history(entries))
+    }
+
+    #[test]
+    fn events() {
+        let entries = ["ls -l /tmp", "echo a b c", "cat foo"];
+        let ok = |line: &str| expanded(line, &entries).unwrap().unwrap();
+        assert_eq!(ok("!!"), "cat foo");
+        assert_eq!(ok("!1"), "ls -l /tmp");
+        assert_eq!(ok("!-2 x"), "echo a b c x");
+        assert_eq!(ok("!ec"), "echo a b c");
+        assert_eq!(ok("!?tm?"), "ls -l /tmp");
+        assert_eq!(ok("sudo !!"), "sudo cat foo");
+        assert_eq!(ok("^foo^bar"), "cat bar");
+        assert_eq!(ok("^foo^bar^"), "cat bar");
+    }
+
+    #[test]
+    fn not_expanded() {
+        let entries = ["cat foo"];
+        for line in [
+            "echo hi",
+            "echo !",
+            "echo a!= b",
+            "echo '!!'",
+            "echo \\!!",
+            "echo !(",
+        ] {
+            assert_eq!(expanded(line, &entries), Ok(None), "{line}");
+        }
+        assert_eq!(
+            expanded("echo \"!!\"", &entries),
+            Ok(Some("echo \"cat foo\"".to_owned()))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let entries = ["cat foo"];
+        let err = |line: &str| expanded(line, &entries).unwrap_err();
+        assert_eq!(err("!2"), "!2: event not found");
+        assert_eq!(err("!0"), "!0: event not found");
+        assert_eq!(err("!-2"), "!-2: event not found");
+        assert_eq!(err("!-"), "!-: event not found");
+        assert_eq!(err("!nope"), "!nope: event not found");
+        assert_eq!(
+            err("!99999999999999999999999"),
+            "!99999999999999999999999: event not found"
+        );
+        assert_eq!(
+            err("!-99999999999999999999999"),
+            "!-99999999999999999999999: event not found"
+        );
+        assert_eq!(err("!!:5"), "!!:5: bad word specifier");
+        assert_eq!(
+            err("!!:0-99999999999999999999999"),
+            "!!:0-99999999999999999999999: bad word specifier"
+        );
+        assert_eq!(err("^x^y"), "^x^y: substitution failed");
+        assert_eq!(expanded("!!", &[]).unwrap_err(), "!!: event not found");
+    }
+
+    #[test]
+    fn word_designators() {
+        let entries = ["cp 'a b' c d"];
+        let ok = |line: &str| expanded(line, &entries).unwrap().unwrap();
+        assert_eq!(ok("!!:0"), "cp");
+        assert_eq!(ok("!!:1"), "'a b'");
+        assert_eq!(ok("!!:^"), "'a b'");
+        assert_eq!(ok("!!:$"), "d");
+        assert_eq!(ok("!!:*"), "'a b' c d");
+        assert_eq!(ok("!!:1-2"), "'a b' c");
+        assert_eq!(ok("!!:-2"), "cp 'a b' c");
+        assert_eq!(ok("!!:2*"), "c d");
+        assert_eq!(ok("!!:2-"), "c");
+        assert_eq!(ok("!cp:3"), "d");
+        assert_eq!(ok("echo !$"), "echo d");
+        assert_eq!(ok("echo !^"), "echo 'a b'");
+        assert_eq!(ok("echo !*"), "echo 'a b' c d");
+        assert_eq!(expanded("!*", &["ls"]), Ok(Some(String::new())));
+    }
+}
@@ -1,10 +1,65 @@
-#[derive(PartialEq, Eq, Debug, Default)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
 enum State {
     #[default]
     Normal,
     Quoted(char),
     Escape, // Last char was '\'
     QuotedEscape(char),
+    Comment, // Up to a newline.
+}
+
+// What a char is to the lexer.
+#[derive(PartialEq, Eq)]
+enum Lexed {
+    Blank,   // Ends the token.
+    Pipe,    // Ends the token and the command.
+    Char,    // Part of the token.
+    Comment, // Ignored.
+}
+
+// Lex `c`, `token_empty` if no token is being lexed.
+fn lex(state: &mut State, c: char, token_empty: bool) -> Lexed {
+    match *state {
+        State::Normal => {
+            if c == ' ' || c == '\t' || c == '\n' {
+                return Lexed::Blank;
+            } else if c == '|' {
+                return Lexed::Pipe;
+            } else if c == '#' && token_empty {
+                *state = State::Comment;
+                return Lexed::Comment;
+            } else if c == '\'' || c == '\"' {
+                *state = State::Quoted(c);
+            } else if c == '\\' {
+                *state = State::Escape;
+            }
+        }
+        State::Quoted(q) => {
+            if c == q {
+                // The closing quote.
+                *state = State::Normal;
+            } else if c == '\\' && q == '"' {
+                // Backslashes are literal in single quotes.
+                *state = State::QuotedEscape(q);
+            }
+        }
+        State::Escape => *state = State::Normal,
+        State::QuotedEscape(q) => *state = State::Quoted(q),
+        State::Comment if c == '\n' => {
+            *state = State::Normal;
+            return Lexed::Blank;
+        }
+        State::Comment => return Lexed::Comment,
+    }
+    Lexed::Char
+}
+
+/// A token of a command line, see tokens().
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Token {
+    Word,
+    Pipe,
+    Comment,
 }
 
 #[derive(Default)]
@@ -14,6 +69,11 @@ pub struct LineParser {
     current_token: String,
 
     state: State,
+
+    // Aliases being substituted, to stop recursion like alias ls='ls -F'.
+    expanding_aliases: Vec<String>,
+    // The last alias value ended with a blank: check the next word too.
+    alias_next: bool,
 }
 
 impl LineParser {
@@ -23,107 +83,77 @@ impl LineParser {
         }
     }
 
+    // Tokens keep their quotes and escapes: quote removal happens
+    // in crate::expand, after the expansions that quoting affects.
     fn process_char(&mut self, c: char) {
-        match self.state {
-            State::Normal => {
-                if c.is_whitespace() {
-                    self.finish_token();
-                } else if c == '|' {
-                    self.finish_command();
-                } else if c == '\'' || c == '\"' {
-                    self.state = State::Quoted(c);
-                } else if c == '\\' {
-                    self.state = State::Escape;
-                } else {
-                    self.current_token.push(c);
-                }
-            }
-            State::Quoted(q) => {
-                if c == q {
-                    // Consume the quote.
-                    self.state = State::Normal;
-                } else if c == '\\' {
-                    self.state = State::QuotedEscape(q);
-                } else if c == '*' {
-                    self.current_token.push('*');
-                } else {
-                    self.current_token.push(c);
-                }
-            }
-            State::Escape => {
-                self.current_token.push(c);
-                self.state = State::Normal;
-            }
-            State::QuotedEscape(q) => {
-                self.current_token.push(c);
-                self.state = State::Quoted(q);
-            }
+        match lex(&mut self.state, c, self.current_token.is_empty()) {
+            Lexed::Blank => self.finish_token(),
+            Lexed::Pipe => self.finish_command(),
+            Lexed::Char => self.current_token.push(c),
+            Lexed::Comment => {}
         }
     }
 
+    // Whether the next token is a command name, i.e. only assignments
+    // like 'A=B' precede it.
+    fn in_command_position(&self) -> bool {
+        self.current_command
+            .iter()
+            .all(|token| is_assignment(token.as_str()))
+    }
+
     fn finish_token(&mut self) {
         let token = std::mem::take(&mut self.current_token);
-        let trimmed = token.trim();
-        if trimmed.is_empty() {
+        if token.is_empty() {
             return;
         }
 
-        if self.current_command.is_empty() {
-            self.current_command.push(trimmed.to_owned());
-        } else {
-            let mut processed = Self::process_arg(trimmed);
-            self.current_command.append(&mut processed);
+        // Substitute aliases by lexing their values in place of the token.
+        let check_alias = std::mem::take(&mut self.alias_next) || self.in_command_position();
+        if check_alias && !self.expanding_aliases.contains(&token) {
+            if let Some(value) = crate::alias::get(token.as_str()) {
+                self.expanding_aliases.push(token);
+                for c in value.chars() {
+                    self.process_char(c);
+                }
+                if self.state == State::Normal {
+                    self.finish_token();
+                }
+                self.expanding_aliases.pop();
+                self.alias_next = value.ends_with([' ', '\t']);
+                return;
+            }
         }
+
+        self.current_command.push(token);
     }
 
     fn finish_command(&mut self) {
         assert_eq!(self.state, State::Normal);
 
         self.finish_token();
+        self.alias_next = false;
 
         if !self.current_command.is_empty() {
             self.result.push(std::mem::take(&mut self.current_command));
         }
     }
 
-    fn process_arg(arg: &str) -> Vec<String> {
-        // Disable glob processing: it removes trailing slashes, which
-        // are meaningful in commands like mv.
-        /*
-        let mut result: Vec<String> = Vec::new();
-
-        match glob::glob(arg) {
-            Ok(paths) => {
-                for entry in paths {
-                    match entry {
-                        Ok(path) => match path.to_str() {
-                            Some(s) => result.push(s.to_owned()),
-                            _ => {}
-                        },
-                        _ => {}
-                    }
-                }
-            }
-            _ => {}
-        }
-
-        if result.is_empty() {
-            result.push(arg.to_owned());
-        }
-
-        result.push(arg.to_owned());
-
-        result
-        */
-        vec![arg.to_owned()]
+    /// Whether the last line ended inside quotes or after a backslash,
+    /// i.e. the command continues on the next line.
+    pub fn is_incomplete(&self) -> bool {
+        self.state != State::Normal
     }
 
     // Parse a line; return a vector of pipelined commands to run, each
-    // command represented by a vector of strings, with wildcards resolved.
+    // command represented by a vector of unexpanded words.
     pub fn parse_line(&mut self, line: &str) -> Option<Vec<Vec<String>>> {
         for c in line.chars() {
             self.process_char(c);
         }
+        if self.state == State::Comment {
+            self.state = State::Normal;
+        }
 
         match self.state {
             State::Normal => {
@@ -138,3 +168,49 @@ impl LineParser {
         }
     }
 }
+
+/// Split a line into tokens as parse_line() does, without substituting
+/// aliases: byte ranges in `line`, in order. Used to highlight the syntax.
+pub fn tokens(line: &str) -> Vec<(std::ops::Range<usize>, Token)> {
+    let mut result = vec![];
+    let mut state = State::Normal;
+    let mut token_start = None;
+    let mut comment_start = None;
+    for (idx, c) in line.char_indices() {
+        let lexed = lex(&mut state, c, token_start.is_none());
+        match lexed {
+            Lexed::Char => {
+                token_start.get_or_insert(idx);
+                continue;
+            }
+            Lexed::Comment => {
+                comment_start.get_or_insert(idx);
+                continue;
+            }
+            Lexed::Blank | Lexed::Pipe => {}
+        }
+        if let Some(start) = token_start.take() {
+            result.push((start..idx, Token::Word));
+        }
+        if let Some(start) = comment_start.take() {
+            result.push((start..idx, Token::Comment));
+        }
+        if lexed == Lexed::Pipe {
+            result.push((idx..(idx + 1), Token::Pipe));
+        }
+    }
+    if let Some(start) = token_start {
+        result.push((start..line.len(), Token::Word));
+    }
+    if let Some(start) = comment_start {
+        result.push((start..line.len(), Token::Comment));
+    }
+    result
+}
+
+/// Whether an (unexpanded) token is an assignment like 'A=B'.
+pub fn is_assignment(token: &str) -> bool {
+    token
+        .split_once('=')
+        .is_some_and(|(name, _)| crate::vars::is_name(name))
+}
@@ -4,12 +4,23 @@ use std::sync::Mutex;
 
 use exec::run_script;
 
+mod alias;
+mod bind;
 mod client_relay;
+mod complete;
+mod dirs;
 mod exec;
+mod expand;
+mod git;
+mod highlight;
+mod history;
 mod line_parser;
 mod listener;
+mod options;
+mod prompt;
 mod redirect;
 mod term;
+mod vars;
 
 #[cfg(unix)]
 mod term_impl_unix;
@@ -59,6 +70,8 @@ fn main() {
     let mut args = Vec::new();
     let mut script = None;
 
+    vars::init();
+
     let args_raw: Vec<_> = std::env::args().collect();
 
     for idx in 1..args_raw.len() {
@@ -126,13 +139,13 @@ fn main() {
         Mode::Script => {
             if let Some(script) = script {
                 // This is usually config, setting PATH and such.
-                run_script(script.as_str(), args, true);
+                run_script(script.as_str(), args, true, false);
             }
         }
         Mode::Terminal | Mode::Piped => {
             if let Some(script) = script {
                 // This is usually config, setting PATH and such.
-                run_script(script.as_str(), args, true);
+                run_script(script.as_str(), args, true, true);
             }
             if mode == Mode::Terminal {
                 assert_terminal();
@@ -143,8 +156,18 @@ fn main() {
 
             let args = vec![];
             loop {
-                if let Some(commands) = parser.parse_line(term::readline().as_str()) {
-                    exec::run(commands, false, &args).ok(); // Ignore results in the interactive mode.
+                let text = term::readline(parser.is_incomplete());
+                // Pasted text may have several lines: run them one by one.
+                for line in text.split('\n') {
+                    if let Some(commands) = parser.parse_line(line) {
+                        // Errors are reported as they happen; only the status is kept.
+                        let started = std::time::Instant::now();
+                        let status = match exec::run(commands, true, &args) {
+                            Ok(()) => 0,
+                            Err(code) => code,
+                        };
+                        exec::set_last_status(status, started.elapsed());
+                    }
                 }
             }
             // unreachable
@@ -173,12 +196,19 @@ fn exit(code: i32) -> ! {
 fn prompt() -> String {
     let mode = MODE.lock().unwrap().clone();
     match mode {
-        Mode::Terminal | Mode::Piped => std::env::current_dir()
-            .unwrap()
-            .as_path()
-            .to_str()
-            .unwrap()
-            .to_owned(),
+        Mode::Terminal | Mode::Piped => {
+            let cwd = std::env::current_dir()
+                .unwrap()
+                .as_path()
+                .to_str()
+                .unwrap()
+                .to_owned();
+            // Show how many directories are pushd'ed, if any.
+            match dirs::depth() {
+                0 => cwd,
+                depth => format!("{cwd} [+{depth}]"),
+            }
+        }
         _ => panic!(),
     }
 }
@@ -0,0 +1,88 @@
+// Shell options: 'set -o name' enables an option, 'set +o name' disables it.
+
+use std::sync::Mutex;
+
+static OPTIONS: Mutex<[(&str, bool); 5]> = Mutex::new([
+    ("emacs", true),            // Emacs-style line editing.
+    ("histexpand", true),       // !! and friends; also 'set -H'/'set +H'.
+    ("transientprompt", false), // Replace the prompt of accepted lines with $TRANSIENT_PROMPT.
+    ("vi", false),              // Vi-style line editing.
+    ("xtrace", false),          // Print commands ($PS4 first) as they run; also 'set -x'/'set +x'.
+]);
+
+pub fn is_set(name: &str) -> bool {
+    OPTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(option, value)| *option == name && *value)
+}
+
+fn set_option(name: &str, value: bool) -> Result<(), i32> {
+    // The editing modes are exclusive: setting one unsets the other.
+    let other = match name {
+        "emacs" if value => Some("vi"),
+        "vi" if value => Some("emacs"),
+        _ => None,
+    };
+    let mut options = OPTIONS.lock().unwrap();
+    match options.iter_mut().find(|(option, _)| *option == name) {
+        Some((_, current)) => {
+            *current = value;
+            if let Some(other) = other {
+                let (_, other) = options
+                    .iter_mut()
+                    .find(|(option, _)| *option == other)
+                    .unwrap();
+                *other = false;
+            }
+            Ok(())
+        }
+        None => {
+            eprintln!("set: {name}: invalid option name.");
+            Err(1)
+        }
+    }
+}
+
+/// set [-o|+o [option]] [-H|+H] [-x|+x]; without arguments, list the variables.
+pub fn set(args: &[String]) -> Result<(), i32> {
+    if args.is_empty() {
+        crate::vars::print_all();
+        return Ok(());
+    }
+
+    let mut idx = 0;
+    while idx < args.len() {
+        let arg = args[idx].as_str();
+        idx += 1;
+        match arg {
+            "-o" | "+o" => {
+                let value = arg == "-o";
+                match args.get(idx) {
+                    Some(name) => {
+                        set_option(name.as_str(), value)?;
+                        idx += 1;
+                    }
+                    None => {
+                        for (option, set) in OPTIONS.lock().unwrap().iter() {
+                            if value {
+                                println!("{option:15} {}", if *set { "on" } else { "off" });
+                            } else {
+                                println!("set {}o {option}", if *set { '-' } else { '+' });
+                            }
+                        }
+                    }
+                }
+            }
+            "-H" | "+H" => set_option("histexpand", arg == "-H")?,
+            "-x" | "+x" => set_option("xtrace", arg == "-x")?,
+            _ => {
+                eprintln!("set: {arg}: invalid option.");
+                eprintln!("set: usage: set [-o|+o [option]] [-H|+H] [-x|+x]");
+                return Err(2);
+            }
+        }
+    }
+    Ok(())
+}
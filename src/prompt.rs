@@ -0,0 +1,222 @@
+// Prompts: PS1 and RPROMPT (on the right of the same row), PS2 (when
+// a command continues on the next line), PS4 (before each command traced
+// by 'set -x') and TRANSIENT_PROMPT (see 'set -o transientprompt').
+//
+// Prompt strings may contain bash-style backslash escapes, parameters
+// and command substitution ("$(git branch --show-current)").
+
+use unicode_width::UnicodeWidthStr;
+
+/// Expand a prompt string.
+pub fn expand(ps: &str) -> String {
+    let chars: Vec<char> = ps.chars().collect();
+    let mut result = String::new();
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        let c = chars[idx];
+        idx += 1;
+        match c {
+            '\\' if idx < chars.len() => {
+                let next = chars[idx];
+                idx += 1;
+                match next {
+                    'u' => result.push_str(user().as_str()),
+                    'h' => result.push_str(hostname().split('.').next().unwrap_or_default()),
+                    'H' => result.push_str(hostname().as_str()),
+                    'w' => result.push_str(cwd().as_str()),
+                    'W' => {
+                        let cwd = cwd();
+                        match cwd.rsplit_once('/') {
+                            Some((_, "")) | None => result.push_str(cwd.as_str()), // "/" or "~".
+                            Some((_, name)) => result.push_str(name),
+                        }
+                    }
+                    '$' => result.push(if is_root() { '#' } else { '$' }),
+                    't' => {
+                        let (hours, minutes, seconds) = local_time();
+                        result.push_str(format!("{hours:02}:{minutes:02}:{seconds:02}").as_str());
+                    }
+                    'j' => result.push('0'), // No job control (yet).
+                    'z' => {
+                        // The directory stack, as in the default prompt.
+                        if crate::dirs::depth() > 0 {
+                            result.push_str(format!("[+{}]", crate::dirs::depth()).as_str());
+                        }
+                    }
+                    '?' => result.push_str(crate::exec::last_status().to_string().as_str()),
+                    'D' => result.push_str(duration(crate::exec::last_duration()).as_str()),
+                    'g' => result.push_str(crate::git::head().unwrap_or_default().as_str()),
+                    'e' => result.push('\x1b'),
+                    'a' => result.push('\x07'),
+                    'n' => result.push('\n'),
+                    '\\' => result.push('\\'),
+                    // Non-printing text in bash; escape sequences never count
+                    // towards the width here anyway, see width().
+                    '[' | ']' => {}
+                    _ => {
+                        result.push('\\');
+                        result.push(next);
+                    }
+                }
+            }
+            '$' if chars.get(idx) == Some(&'(') => match find_paren_close(&chars, idx) {
+                Some(close) => {
+                    let command: String = chars[(idx + 1)..close].iter().collect();
+                    result.push_str(crate::exec::output(command.as_str()).as_str());
+                    idx = close + 1;
+                }
+                None => result.push(c),
+            },
+            '$' => match crate::expand::expand_parameter(&chars[(idx - 1)..], &[]) {
+                Some((value, len)) => {
+                    result.push_str(value.as_str());
+                    idx += len - 1;
+                }
+                None => result.push(c),
+            },
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// How many columns `text` takes on the terminal: escape sequences
+/// (e.g. colors) take none.
+pub fn width(text: &str) -> usize {
+    let mut result = 0;
+    let mut rest = text;
+    while let Some(esc) = rest.find('\x1b') {
+        result += rest[..esc].width();
+        rest = skip_escape(&rest[esc..]);
+    }
+    result + rest.width()
+}
+
+// Skip the escape sequence `s` starts with.
+fn skip_escape(s: &str) -> &str {
+    let bytes = s.as_bytes();
+    debug_assert_eq!(bytes[0], 0x1b);
+    let end = match bytes.get(1) {
+        // CSI: parameters, then a final byte in '@'..='~'.
+        Some(b'[') => bytes[2..]
+            .iter()
+            .position(|b| (b'@'..=b'~').contains(b))
+            .map_or(bytes.len(), |pos| pos + 3),
+        // OSC (e.g. the window title): up to BEL or ESC '\'.
+        Some(b']') => match bytes[2..].iter().position(|b| *b == 0x07 || *b == 0x1b) {
+            Some(pos) if bytes[pos + 2] == 0x1b => (pos + 4).min(bytes.len()),
+            Some(pos) => pos + 3,
+            None => bytes.len(),
+        },
+        Some(_) => 2,
+        None => 1,
+    };
+    s.get(end..).unwrap_or_default()
+}
+
+// The position of the ')' closing the '(' at chars[open].
+fn find_paren_close(chars: &[char], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (idx, c) in chars.iter().enumerate().skip(open) {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(idx);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// E.g. "250ms", "1.5s" or "2m3s".
+fn duration(duration: std::time::Duration) -> String {
+    let millis = duration.as_millis();
+    if millis < 1000 {
+        format!("{millis}ms")
+    } else if millis < 60_000 {
+        format!("{}.{}s", millis / 1000, (millis % 1000) / 100)
+    } else {
+        let secs = millis / 1000;
+        format!("{}m{}s", secs / 60, secs % 60)
+    }
+}
+
+// The current directory, with the home directory shown as '~'.
+fn cwd() -> String {
+    let cwd = std::env::current_dir()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match crate::vars::get("HOME") {
+        Some(home) if !home.is_empty() && home != "/" => match cwd.strip_prefix(home.as_str()) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{rest}"),
+            _ => cwd,
+        },
+        _ => cwd,
+    }
+}
+
+fn user() -> String {
+    crate::vars::get("USER")
+        .or_else(|| crate::vars::get("LOGNAME"))
+        .or_else(|| crate::vars::get("USERNAME"))
+        .unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0_u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    crate::vars::get("HOSTNAME")
+        .or_else(|| crate::vars::get("COMPUTERNAME"))
+        .unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+// Hours, minutes and seconds.
+#[cfg(unix)]
+fn local_time() -> (i32, i32, i32) {
+    let mut tm: libc::tm = unsafe { core::mem::zeroed() };
+    unsafe {
+        let now = libc::time(core::ptr::null_mut());
+        libc::localtime_r(&now, &mut tm);
+    }
+    (tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+// There is no time zone database: show UTC.
+#[cfg(not(unix))]
+fn local_time() -> (i32, i32, i32) {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (
+        ((secs / 3600) % 24) as i32,
+        ((secs / 60) % 60) as i32,
+        (secs % 60) as i32,
+    )
+}
@@ -1,11 +1,42 @@
 use std::collections::VecDeque;
 use std::io::{Read, Write};
 use std::sync::Mutex;
+use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
+use unicode_width::UnicodeWidthStr;
+
+/// Commands handled by Term::process_locally().
+pub const LOCAL_COMMANDS: &[&str] = &["clear"];
+
+const KILL_RING_SIZE: usize = 10;
+
+// How long to wait for the terminal to answer a query.
+const QUERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+// How long to wait for the rest of an escape sequence after ESC.
+const ESCAPE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
+
+// How long to wait for the next key of a typed key sequence (as readline's keyseq-timeout).
+const KEYSEQ_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
 
 pub trait TermImpl: Send + Sync {
     fn make_raw(&mut self) {}
     fn make_cooked(&mut self) {}
     fn on_exit(&mut self) {}
+
+    /// The terminal's rows and columns, if known without asking the terminal.
+    fn size(&mut self) -> Option<(u32, u32)> {
+        None
+    }
+
+    /// Whether the terminal has been resized since the last call.
+    fn resized(&mut self) -> bool {
+        false
+    }
+
+    /// Whether resized() tells of resizes at all.
+    fn reports_resizes(&self) -> bool {
+        false
+    }
 }
 
 #[cfg(unix)]
@@ -14,6 +45,11 @@ use crate::term_impl_unix as term_impl;
 #[cfg(not(unix))]
 mod term_impl {
     pub use super::PipedTerminal as ArchTerm;
+
+    // Without a way to wait with a timeout, wait for as long as it takes.
+    pub fn wait_for_input(_timeout: std::time::Duration) -> bool {
+        true
+    }
 }
 
 pub struct PipedTerminal {}
@@ -31,6 +67,187 @@ enum ProcessingMode {
     Normal,
     Escape(Vec<u8>),
     History(usize),
+    Search(Search), // Ctrl-R/Ctrl-S.
+}
+
+// The state of an incremental history search.
+#[derive(Clone, PartialEq, Eq)]
+struct Search {
+    query: Vec<u8>,
+    idx: usize, // The matching history entry; history.len() if none yet.
+    reverse: bool,
+    failed: bool,
+    orig_line: Vec<u8>, // Restored if the search is aborted.
+    orig_pos: u32,
+}
+
+// The kinds of edits; consecutive edits of the same kind are undone together.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Insert,
+    Delete,
+    Kill,
+    Yank,
+    Paste,
+    Transpose,
+    Complete,
+    Recall, // Showing another history entry.
+    Vi,     // A vi command; each is undone separately.
+}
+
+// The line before an edit.
+struct Snapshot {
+    line: Vec<u8>,
+    pos: u32,
+    mode: ProcessingMode,
+}
+
+// The state of the vi editing mode.
+#[derive(Default)]
+struct Vi {
+    command: bool,           // In the command state rather than inserting.
+    keys: Vec<u8>,           // The command typed so far, e.g. "2d".
+    last_change: Vec<u8>,    // The keys of the last change, repeated by '.'.
+    recording: bool,         // Inserted text is added to last_change.
+    search: Option<Vec<u8>>, // The '/' query being typed.
+}
+
+// A parsed vi command: [count] key [arg], or [count] operator [count] motion [arg].
+struct ViCommand {
+    count: usize,
+    op: Option<u8>, // d, c or y.
+    key: u8,
+    arg: Option<u8>, // The character for f, F, t, T and r.
+}
+
+// An optional count at keys[*idx..]; "0" is a motion rather than a count.
+fn parse_vi_count(keys: &[u8], idx: &mut usize) -> usize {
+    let start = *idx;
+    while *idx < keys.len() && keys[*idx].is_ascii_digit() && (*idx > start || keys[*idx] != b'0') {
+        *idx += 1;
+    }
+    std::str::from_utf8(&keys[start..*idx])
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or(1)
+}
+
+// None if the command is incomplete.
+fn parse_vi_command(keys: &[u8]) -> Option<ViCommand> {
+    let mut idx = 0;
+    let mut count = parse_vi_count(keys, &mut idx);
+    let mut key = *keys.get(idx)?;
+    idx += 1;
+    let mut op = None;
+    if matches!(key, b'd' | b'c' | b'y') {
+        op = Some(key);
+        count = count.saturating_mul(parse_vi_count(keys, &mut idx));
+        key = *keys.get(idx)?;
+        idx += 1;
+    }
+    let arg = match key {
+        b'f' | b'F' | b't' | b'T' | b'r' => Some(*keys.get(idx)?),
+        _ => None,
+    };
+    Some(ViCommand {
+        count,
+        op,
+        key,
+        arg,
+    })
+}
+
+// The line is UTF-8 text; positions in it are byte offsets
+// at grapheme cluster boundaries.
+fn as_text(bytes: &[u8]) -> &str {
+    std::str::from_utf8(bytes).unwrap_or_default()
+}
+
+// How many terminal columns the text takes.
+fn display_width(bytes: &[u8]) -> u32 {
+    as_text(bytes).width() as u32
+}
+
+// Where the cursor is put for byte offset `pos` when the text is written
+// from column `start` of a terminal `cols` columns wide: (row, col), both
+// counted from zero. A wide character that does not fit at the end of a row
+// goes to the next row; so does the cursor after a row is full.
+fn layout(bytes: &[u8], start: u32, cols: u32, pos: usize) -> (u32, u32) {
+    let (mut row, mut col) = (0, start);
+    for (idx, grapheme) in as_text(bytes).grapheme_indices(true) {
+        // Pasted lines and tabs.
+        if grapheme == "\n" || grapheme == "\t" {
+            if idx >= pos {
+                return (row, col.min(cols - 1));
+            }
+            if grapheme == "\n" {
+                (row, col) = (row + 1, 0);
+            } else {
+                col = ((col / 8 + 1) * 8).min(cols - 1);
+            }
+            continue;
+        }
+        let width = grapheme.width() as u32;
+        if col + width > cols {
+            row += 1;
+            col = 0;
+        }
+        if idx >= pos {
+            return (row, col);
+        }
+        col += width;
+    }
+    if col >= cols {
+        (row + 1, 0)
+    } else {
+        (row, col)
+    }
+}
+
+// Bytes for the terminal: in the raw mode, '\n' does not return the cursor.
+fn with_crlf(bytes: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(bytes.len());
+    for b in bytes {
+        if *b == b'\n' {
+            result.push(b'\r');
+        }
+        result.push(*b);
+    }
+    result
+}
+
+fn prev_grapheme(bytes: &[u8], pos: usize) -> usize {
+    let text = as_text(bytes);
+    GraphemeCursor::new(pos, text.len(), true)
+        .prev_boundary(text, 0)
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+fn next_grapheme(bytes: &[u8], pos: usize) -> usize {
+    let text = as_text(bytes);
+    GraphemeCursor::new(pos, text.len(), true)
+        .next_boundary(text, 0)
+        .ok()
+        .flatten()
+        .unwrap_or(text.len())
+}
+
+// Non-ASCII characters are treated as letters.
+fn is_word_byte(c: &u8) -> bool {
+    c.is_ascii_alphanumeric() || *c >= 128
+}
+
+// Blanks, word characters and other characters form separate vi words.
+fn vi_class(c: u8) -> u8 {
+    if c.is_ascii_whitespace() {
+        0
+    } else if is_word_byte(&c) || c == b'_' {
+        1
+    } else {
+        2
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -44,13 +261,118 @@ enum EscapesIn {
     Home,
     End,
     CtrlC,
+    Tab,
+    CtrlG,
+    CtrlR,
+    CtrlS,
+    CtrlD,        // Delete, or exit on an empty line.
+    WordLeft,     // Alt-B, Ctrl-Left.
+    WordRight,    // Alt-F, Ctrl-Right.
+    KillLine,     // Ctrl-K: kill to the end of the line.
+    KillLineBack, // Ctrl-U: kill to the start of the line.
+    KillWord,     // Alt-D: kill to the end of the word.
+    KillWordBack, // Ctrl-W: kill the whitespace-delimited word before the cursor.
+    Transpose,    // Ctrl-T.
+    ClearScreen,  // Ctrl-L.
+    Yank,         // Ctrl-Y: insert the last killed text.
+    YankPop,      // Alt-Y: replace the yanked text with an older kill.
+    LoneEscape,   // ESC not starting an escape sequence (in vi mode), vi-movement-mode.
+    Undo,         // Ctrl-_, Ctrl-X Ctrl-U.
+    Redo,         // Ctrl-R in the vi command state.
+    PasteStart,   // ESC[200~: pasted text follows, up to ESC[201~ (bracketed paste).
+}
+
+// The functions keys can be bound to, by readline's names.
+const ACTIONS: &[(&str, ProcessByteResult)] = &[
+    ("abort", ProcessByteResult::Escape(EscapesIn::CtrlG)),
+    ("accept-line", ProcessByteResult::Newline),
+    (
+        "backward-char",
+        ProcessByteResult::Escape(EscapesIn::LeftArrow),
+    ),
+    (
+        "backward-delete-char",
+        ProcessByteResult::Escape(EscapesIn::Backspace),
+    ),
+    (
+        "backward-word",
+        ProcessByteResult::Escape(EscapesIn::WordLeft),
+    ),
+    (
+        "beginning-of-line",
+        ProcessByteResult::Escape(EscapesIn::Home),
+    ),
+    (
+        "bracketed-paste-begin",
+        ProcessByteResult::Escape(EscapesIn::PasteStart),
+    ),
+    (
+        "clear-screen",
+        ProcessByteResult::Escape(EscapesIn::ClearScreen),
+    ),
+    ("complete", ProcessByteResult::Escape(EscapesIn::Tab)),
+    ("delete-char", ProcessByteResult::Escape(EscapesIn::Delete)),
+    ("end-of-file", ProcessByteResult::Escape(EscapesIn::CtrlD)),
+    ("end-of-line", ProcessByteResult::Escape(EscapesIn::End)),
+    (
+        "forward-char",
+        ProcessByteResult::Escape(EscapesIn::RightArrow),
+    ),
+    (
+        "forward-search-history",
+        ProcessByteResult::Escape(EscapesIn::CtrlS),
+    ),
+    (
+        "forward-word",
+        ProcessByteResult::Escape(EscapesIn::WordRight),
+    ),
+    ("interrupt", ProcessByteResult::Escape(EscapesIn::CtrlC)),
+    ("kill-line", ProcessByteResult::Escape(EscapesIn::KillLine)),
+    ("kill-word", ProcessByteResult::Escape(EscapesIn::KillWord)),
+    (
+        "next-history",
+        ProcessByteResult::Escape(EscapesIn::DownArrow),
+    ),
+    (
+        "previous-history",
+        ProcessByteResult::Escape(EscapesIn::UpArrow),
+    ),
+    (
+        "reverse-search-history",
+        ProcessByteResult::Escape(EscapesIn::CtrlR),
+    ),
+    (
+        "transpose-chars",
+        ProcessByteResult::Escape(EscapesIn::Transpose),
+    ),
+    ("undo", ProcessByteResult::Escape(EscapesIn::Undo)),
+    (
+        "unix-line-discard",
+        ProcessByteResult::Escape(EscapesIn::KillLineBack),
+    ),
+    (
+        "unix-word-rubout",
+        ProcessByteResult::Escape(EscapesIn::KillWordBack),
+    ),
+    (
+        "vi-movement-mode",
+        ProcessByteResult::Escape(EscapesIn::LoneEscape),
+    ),
+    ("yank", ProcessByteResult::Escape(EscapesIn::Yank)),
+    ("yank-pop", ProcessByteResult::Escape(EscapesIn::YankPop)),
+];
+
+/// The names of the line editing functions keys can be bound to, see crate::bind.
+pub fn action_names() -> impl Iterator<Item = &'static str> {
+    ACTIONS.iter().map(|(name, _)| *name)
 }
 
+#[derive(Clone, Copy)]
 enum ProcessByteResult {
-    Byte(u8), // Normal byte to add;
-    Newline,  // Newline: finish processing the line;
-    Continue, // Continue processing input;
-    Clear,    // Clear current input (e.g. an escape sequence not recognized);
+    Char(char), // Normal character to add;
+    Newline,    // Newline: finish processing the line;
+    Continue,   // Continue processing input;
+    Clear,      // Clear current input (e.g. an escape sequence not recognized);
     Escape(EscapesIn),
 }
 
@@ -61,33 +383,38 @@ struct Term {
     line: Vec<u8>,
     prev_line: Vec<u8>, // What was typed before an Up arrow was hit.
     line_start: u32,    // Where the input starts after the prompt.
-    current_pos: u32,   // Relative to line start.
+    current_pos: u32,   // The byte offset in the line.
+    cursor_row: u32,    // The terminal row of the cursor, counted from where the input starts.
+    prompt_rows: u32,   // The rows of the prompt above where the input starts.
+    continuation: bool, // The line continues the previous one: show PS2.
+    prompt: String,     // Expanded PS1 or PS2, see expand_prompts().
+    rprompt: String,    // Expanded RPROMPT.
+    rprompt_shown: bool,
+    suggestion: Vec<u8>, // Shown dimmed after the line, see find_suggestion().
+    rows: u32,           // The terminal size, see update_size().
+    cols: u32,
+    size_queried: bool, // The terminal was asked for its size, see update_size().
+    no_cursor_reports: bool, // The terminal did not answer a query, see get_cursor_pos().
 
     incoming: VecDeque<u8>,
+    last_key_tab: bool, // A second TAB in a row lists completions.
+    last_search: Vec<u8>,
+    kill_ring: Vec<Vec<u8>>,           // The most recent kill is the last.
+    last_yank: Option<(usize, usize)>, // Where the text was yanked, and its kill ring index.
+    last_edit: Option<Edit>,
+    undo: Vec<Snapshot>,
+    redo: Vec<Snapshot>,
+    edited_history: std::collections::BTreeMap<usize, Vec<u8>>, // Until the line is accepted.
+    vi: Vi,
 
     term_impl: Box<dyn TermImpl>,
-    escapes_in: std::collections::BTreeMap<&'static [u8], EscapesIn>,
     debug: bool,
 }
 
 impl Term {
     fn new(piped: bool) -> Self {
-        let mut escapes_in: std::collections::BTreeMap<&'static [u8], EscapesIn> =
-            std::collections::BTreeMap::new();
-
-        escapes_in.insert(&[0x1b, b'[', b'A'], EscapesIn::UpArrow);
-        escapes_in.insert(&[0x1b, b'[', b'B'], EscapesIn::DownArrow);
-        escapes_in.insert(&[0x1b, b'[', b'C'], EscapesIn::RightArrow);
-        escapes_in.insert(&[0x1b, b'[', b'D'], EscapesIn::LeftArrow);
-        escapes_in.insert("\x1b[3~".as_bytes(), EscapesIn::Delete);
-        escapes_in.insert("\x1b[1~".as_bytes(), EscapesIn::Home);
-        escapes_in.insert("\x1b[7~".as_bytes(), EscapesIn::Home);
-        escapes_in.insert("\x1b[H".as_bytes(), EscapesIn::Home);
-        escapes_in.insert("\x1b[4~".as_bytes(), EscapesIn::End);
-        escapes_in.insert("\x1b[8~".as_bytes(), EscapesIn::End);
-
         Self {
-            history: vec![],
+            history: crate::history::load(),
             mode: ProcessingMode::Normal,
             prev_mode: ProcessingMode::Normal,
             line: vec![],
@@ -97,10 +424,29 @@ impl Term {
             } else {
                 Box::new(term_impl::ArchTerm::new())
             },
-            escapes_in,
             line_start: 0,
             current_pos: 0,
+            cursor_row: 0,
+            prompt_rows: 0,
+            continuation: false,
+            prompt: String::new(),
+            rprompt: String::new(),
+            rprompt_shown: false,
+            suggestion: vec![],
+            rows: 0,
+            cols: 0,
+            size_queried: false,
+            no_cursor_reports: false,
             incoming: VecDeque::new(),
+            last_key_tab: false,
+            last_search: vec![],
+            kill_ring: vec![],
+            last_yank: None,
+            last_edit: None,
+            undo: vec![],
+            redo: vec![],
+            edited_history: std::collections::BTreeMap::new(),
+            vi: Vi::default(),
 
             debug: false,
         }
@@ -111,12 +457,19 @@ impl Term {
             c
         } else {
             let mut buf = [0_u8; 16];
-            let sz = match std::io::stdin().read(&mut buf) {
-                Ok(sz) => sz,
-                Err(err) => {
-                    eprintln!("stdin() read failed with: {err:?}");
-                    self.term_impl.make_raw();
-                    std::process::exit(1);
+            let sz = loop {
+                if self.term_impl.resized() {
+                    self.on_resize();
+                }
+                match std::io::stdin().read(&mut buf) {
+                    Ok(sz) => break sz,
+                    // E.g. SIGWINCH.
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        eprintln!("stdin() read failed with: {err:?}");
+                        self.term_impl.make_raw();
+                        std::process::exit(1);
+                    }
                 }
             };
             if sz == 0 {
@@ -134,97 +487,157 @@ impl Term {
         }
     }
 
+    // Whether the ESC just read starts a control sequence ("ESC ["). The rest
+    // of the sequence may arrive in a separate read, so wait for it a little.
+    // Whether more keys come soon, e.g. to make up a longer key sequence.
+    fn more_keys_soon(&mut self) -> bool {
+        !self.incoming.is_empty() || term_impl::wait_for_input(KEYSEQ_TIMEOUT)
+    }
+
+    fn csi_follows(&mut self) -> bool {
+        if self.incoming.is_empty() && term_impl::wait_for_input(ESCAPE_TIMEOUT) {
+            let c = self.next_byte();
+            self.incoming.push_front(c);
+        }
+        self.incoming.front() == Some(&b'[')
+    }
+
     fn process_next_byte(&mut self, c: u8) -> ProcessByteResult {
         match &self.mode {
-            ProcessingMode::Normal | ProcessingMode::History(_) => {
-                match c {
-                    32..=126 => {
-                        ProcessByteResult::Byte(c)
-                    }
-                    128.. => {
-                        // Ignore non-ascii bytes for now.
-                        ProcessByteResult::Continue
-                    }
-                    3 => {
-                        ProcessByteResult::Escape(EscapesIn::CtrlC)
-                    }
-                    8 | 127 /* BS */ => {
-                        ProcessByteResult::Escape(EscapesIn::Backspace)
-                    },
-                    13 /* 13 | 10 */ /* CR/NL */ => {
-                        ProcessByteResult::Newline
-                    }
-                    10 => ProcessByteResult::Continue, // Avoid double newlines
-                    0x1b /* ESC */ => {
-                        self.prev_mode = self.mode.clone();
-                        self.mode = ProcessingMode::Escape(vec![0x1b]);
-                        ProcessByteResult::Continue
-                    }
-                    9 /* TAB */ => {
-                        self.debug_log("TAB");
-                        ProcessByteResult::Continue
-                    }
-                    _ => {
-                        self.debug_log(format!("unrecognized char: 0x{c:x}").as_str());
-                        self.write(&[7_u8]);  // Beep.
-                        ProcessByteResult::Continue
-                    }
+            ProcessingMode::Normal | ProcessingMode::History(_) | ProcessingMode::Search(_) => {
+                if c == 0x1b /* ESC */ && crate::options::is_set("vi") && !self.csi_follows() {
+                    // Vi mode: ESC on its own leaves the insert state.
+                    return ProcessByteResult::Escape(EscapesIn::LoneEscape);
                 }
+                if let Some(result) = self.lookup_keys(vec![c]) {
+                    return result;
+                }
+                self.unbound_key(c)
             }
             ProcessingMode::Escape(v) => {
-                let mut candidate_key = v.clone();
-                candidate_key.push(c);
-
-                if v.len() == 1 {
-                    match c {
-                        b'[' => {
-                            self.mode = ProcessingMode::Escape(candidate_key);
-                            return ProcessByteResult::Continue;
-                        }
-                        _ => {
-                            // There are no recognized keys that start with anything other than "\x1b[".
-                            self.debug_log(
-                                format!("unknown escape sequence: 0x{:x?}", &candidate_key[0..])
-                                    .as_str(),
-                            );
-                            self.mode = self.prev_mode.clone();
-                            return ProcessByteResult::Clear;
+                let mut keys = v.clone();
+                keys.push(c);
+                if let Some(result) = self.lookup_keys(keys.clone()) {
+                    return result;
+                }
+
+                if keys.starts_with(b"\x1b[") && (0x20..=0x3f).contains(&c) {
+                    // Skip the parameters of an unknown CSI sequence, up to its final byte.
+                    self.mode = ProcessingMode::Escape(keys);
+                    return ProcessByteResult::Continue;
+                }
+                self.mode = self.prev_mode.clone();
+                if c == b'R'
+                    && keys.starts_with(b"\x1b[")
+                    && keys[2..(keys.len() - 1)]
+                        .iter()
+                        .all(|b| b.is_ascii_digit() || *b == b';')
+                {
+                    // "ESC[<row>;<col>R": a cursor report that came too late
+                    // for get_cursor_pos(). Not a key.
+                    return ProcessByteResult::Continue;
+                }
+                if !keys.starts_with(b"\x1b[") {
+                    // No binding completes the keys: run the longest bound
+                    // sequence they start with, or take the first key on its
+                    // own (e.g. "j" of "jk" inserts 'j'), and read the rest again.
+                    let (result, len) = match crate::bind::bound_prefix(&keys) {
+                        Some((target, len)) => (Some(target), len),
+                        None => (None, 1),
+                    };
+                    if result.is_some() || keys[0] != 0x1b {
+                        for b in keys[len..].iter().rev() {
+                            self.incoming.push_front(*b);
                         }
+                        return match result {
+                            Some(target) => self.run_target(target),
+                            None => self.unbound_key(keys[0]),
+                        };
                     }
                 }
+                self.debug_log(format!("unknown key sequence: 0x{keys:x?}").as_str());
+                ProcessByteResult::Clear
+            }
+        }
+    }
 
-                match c {
-                    b'0'..=b'9' | b';' => {
-                        // Continue on numbers and ';'.
-                        self.mode = ProcessingMode::Escape(candidate_key);
-                        return ProcessByteResult::Continue;
-                    }
-                    _ => {
-                        // Break otherwise.
+    // A key that is not bound: printable ones insert themselves.
+    fn unbound_key(&mut self, c: u8) -> ProcessByteResult {
+        match c {
+            32..=126 => ProcessByteResult::Char(c as char),
+            0xc0..=0xf7 => {
+                // The first byte of a UTF-8 sequence: read the rest of the character.
+                let len = match c {
+                    0xc0..=0xdf => 2,
+                    0xe0..=0xef => 3,
+                    _ => 4,
+                };
+                let mut bytes = vec![c];
+                while bytes.len() < len {
+                    bytes.push(self.next_byte());
+                }
+                match std::str::from_utf8(&bytes) {
+                    Ok(s) => ProcessByteResult::Char(s.chars().next().unwrap()),
+                    Err(_) => {
+                        self.beep();
+                        ProcessByteResult::Continue
                     }
                 }
+            }
+            128.. => {
+                // Ignore stray continuation bytes.
+                ProcessByteResult::Continue
+            }
+            10 => ProcessByteResult::Continue, // Avoid double newlines (CR is bound).
+            _ => {
+                self.debug_log(format!("unrecognized char: 0x{c:x}").as_str());
+                self.write(&[7_u8]); // Beep.
+                ProcessByteResult::Continue
+            }
+        }
+    }
 
-                match self.escapes_in.get(&candidate_key[0..]) {
-                    Some(val) => {
-                        self.mode = self.prev_mode.clone();
-                        ProcessByteResult::Escape(*val)
-                    }
-                    None => {
-                        // Not found.
-                        self.debug_log(
-                            format!("unknown escape sequence: 0x{:x?}", &candidate_key[0..])
-                                .as_str(),
-                        );
-                        self.mode = self.prev_mode.clone();
-                        ProcessByteResult::Clear
-                    }
+    // Look up the keys read so far in the bindings (see crate::bind).
+    // None if no binding starts with them.
+    fn lookup_keys(&mut self, keys: Vec<u8>) -> Option<ProcessByteResult> {
+        let (target, is_prefix) = crate::bind::lookup(&keys);
+        // Keys that mean something on their own, but may also start a longer
+        // sequence (e.g. "j" if "jk" is bound), wait a little for more keys.
+        // Others (e.g. ESC, or Ctrl-X if only "\C-x\C-u" is bound) wait for as long as it takes.
+        let means_something = target.is_some() || matches!(keys.as_slice(), [32..=126]);
+        if is_prefix && (!means_something || self.more_keys_soon()) {
+            if !matches!(self.mode, ProcessingMode::Escape(_)) {
+                self.prev_mode = self.mode.clone();
+            }
+            self.mode = ProcessingMode::Escape(keys);
+            return Some(ProcessByteResult::Continue);
+        }
+        let target = target?;
+        if let ProcessingMode::Escape(_) = self.mode {
+            self.mode = self.prev_mode.clone();
+        }
+        Some(self.run_target(target))
+    }
+
+    fn run_target(&mut self, target: crate::bind::Target) -> ProcessByteResult {
+        match target {
+            crate::bind::Target::Function(name) => ACTIONS
+                .iter()
+                .find(|(action, _)| *action == name)
+                .map_or(ProcessByteResult::Continue, |(_, result)| *result),
+            crate::bind::Target::Macro(text) => {
+                // Typed in place of the keys.
+                for b in text.iter().rev() {
+                    self.incoming.push_front(*b);
                 }
+                ProcessByteResult::Continue
             }
         }
     }
 
     fn readline(&mut self) -> Option<String> {
         self.term_impl.make_raw();
+        self.write("\x1b[?2004h".as_bytes()); // Bracketed paste, see paste().
         self.start_line();
 
         if !self.history.is_empty() {
@@ -236,38 +649,104 @@ impl Term {
         }
 
         loop {
+            if self.vi.command
+                && matches!(
+                    self.mode,
+                    ProcessingMode::Normal | ProcessingMode::History(_)
+                )
+            {
+                // In the vi command state, the cursor is on a character, not after the line.
+                let last = prev_grapheme(&self.line, self.line.len());
+                if self.vi.search.is_none() && self.current_pos as usize > last {
+                    self.move_to(last);
+                }
+            }
+
             let byte = self.next_byte();
-            match self.process_next_byte(byte) {
-                ProcessByteResult::Byte(c) => {
+            let result = self.process_next_byte(byte);
+            let prev_key_tab = self.last_key_tab;
+            let prev_yank = self.last_yank;
+            let prev_edit = self.last_edit;
+            if !matches!(result, ProcessByteResult::Continue) {
+                // Any other key ends a sequence of TABs, yanks or edits of one kind.
+                self.last_key_tab = false;
+                self.last_yank = None;
+                self.last_edit = None;
+            }
+            if let ProcessingMode::Search(_) = self.mode {
+                if self.process_search_key(&result) {
+                    continue;
+                }
+                // Other keys end the search and are then processed as usual.
+                self.end_search(true);
+            }
+            let result = if self.vi.command {
+                match self.process_vi_key(result) {
+                    Some(result) => result,
+                    None => continue,
+                }
+            } else {
+                result
+            };
+            match result {
+                ProcessByteResult::Char(c) => {
                     match self.mode {
-                        ProcessingMode::Normal => {}
-                        ProcessingMode::Escape(_) | ProcessingMode::History(_) => {
+                        // History entries are edited in place until the line is accepted.
+                        ProcessingMode::Normal | ProcessingMode::History(_) => {}
+                        ProcessingMode::Escape(_) | ProcessingMode::Search(_) => {
                             self.mode = ProcessingMode::Normal;
                             self.show_cursor();
                         }
                     }
+                    let mut buf = [0_u8; 4];
+                    let bytes = c.encode_utf8(&mut buf).as_bytes();
+                    if self.vi.recording {
+                        self.vi.last_change.extend_from_slice(bytes);
+                    }
+                    self.save_undo(Edit::Insert, prev_edit);
                     assert!(self.current_pos <= (self.line.len() as u32));
-                    if self.current_pos == (self.line.len() as u32) {
+                    let pos = self.current_pos as usize;
+                    self.line.splice(pos..pos, bytes.iter().copied());
+                    // Keep the cursor at a grapheme boundary, e.g. if a combining character was inserted.
+                    let end = pos + bytes.len();
+                    self.current_pos =
+                        next_grapheme(&self.line, prev_grapheme(&self.line, end)) as u32;
+                    if pos == self.line.len() - bytes.len()
+                        && !self.is_highlighted()
+                        && self.suggestion.is_empty()
+                        && self.find_suggestion().is_empty()
+                    {
                         // Add to end.
-                        self.line.push(c);
-                        self.write(&[c]);
+                        self.write(bytes);
+                        self.wrap_cursor();
+                        if self.rprompt_shown && !self.rprompt_fits() {
+                            // The line has grown into RPROMPT: erase it.
+                            self.write("\x1b[K".as_bytes());
+                            self.rprompt_shown = false;
+                        }
                     } else {
                         // Insert.
-                        self.line.insert(self.current_pos as usize, c);
                         self.redraw_line();
-                        self.write(&[0x1b, b'[', b'1', b'C']); // Move right.
                     }
-                    self.current_pos += 1;
                     self.debug_log(format!("got c {c}").as_str());
                 }
                 ProcessByteResult::Newline => {
                     match self.mode {
                         ProcessingMode::Normal => {}
-                        ProcessingMode::Escape(_) | ProcessingMode::History(_) => {
+                        ProcessingMode::Escape(_)
+                        | ProcessingMode::History(_)
+                        | ProcessingMode::Search(_) => {
                             self.mode = ProcessingMode::Normal;
                             self.show_cursor();
                         }
                     }
+                    // Output goes below the line.
+                    self.hide_suggestion();
+                    if crate::options::is_set("transientprompt") {
+                        self.draw_transient_prompt();
+                    } else {
+                        self.move_to(self.line.len());
+                    }
                     let cmd = match std::str::from_utf8(&self.line[..]) {
                         Ok(s) => s.trim(),
                         Err(err) => {
@@ -277,14 +756,31 @@ impl Term {
                     }
                     .to_owned();
                     if cmd.is_empty() {
+                        // The next readline() starts a new line.
                         self.write("\r\n".as_bytes());
-                        self.start_line();
                         break;
                     }
+                    let cmd = if crate::options::is_set("histexpand") {
+                        match crate::history::expand(cmd.as_str(), &self.history) {
+                            Ok(None) => cmd,
+                            Ok(Some(expanded)) => {
+                                // Show the command that is actually run.
+                                self.write("\r\n".as_bytes());
+                                self.write(expanded.as_bytes());
+                                expanded
+                            }
+                            Err(err) => {
+                                self.write(format!("\r\nrush: {err}\r\n").as_bytes());
+                                break;
+                            }
+                        }
+                    } else {
+                        cmd
+                    };
                     if self.process_locally(cmd.as_str()) {
                         break;
                     } else {
-                        self.write("\r\n".as_bytes());
+                        self.write("\r\n\x1b[?2004l".as_bytes());
                         self.term_impl.make_cooked();
                         self.maybe_add_to_history(cmd.as_str());
                         return Some(cmd);
@@ -293,52 +789,29 @@ impl Term {
                 ProcessByteResult::Continue => {}
                 ProcessByteResult::Escape(e) => match e {
                     EscapesIn::UpArrow => match self.mode {
-                        ProcessingMode::Normal => {
-                            if !self.history.is_empty() {
-                                self.mode = ProcessingMode::History(self.history.len() - 1);
-                                self.show_cursor();
-                                let prev = self.history.last().unwrap().clone();
-                                if self.line == prev {
-                                    continue;
-                                }
-                                self.prev_line = self.line.clone();
-                                self.line = prev;
-                                self.current_pos = self.line.len() as u32;
-                                self.redraw_line();
-                            } else {
-                                self.beep();
-                            }
+                        ProcessingMode::Normal if !self.history.is_empty() => {
+                            self.recall(self.history.len() - 1, prev_edit);
+                            self.redraw_line();
                         }
-                        ProcessingMode::Escape(_) => {
-                            panic!("UpArrow: unexpected 'Escape' mode.");
+                        ProcessingMode::Normal | ProcessingMode::History(0) => self.beep(),
+                        ProcessingMode::Escape(_) | ProcessingMode::Search(_) => {
+                            panic!("UpArrow: unexpected mode.");
                         }
                         ProcessingMode::History(idx) => {
-                            if idx > 0 {
-                                self.mode = ProcessingMode::History(idx - 1);
-                                self.line = self.history[idx - 1].clone();
-                                self.current_pos = self.line.len() as u32;
-                                self.redraw_line();
-                            } else {
-                                self.beep();
-                            }
+                            self.recall(idx - 1, prev_edit);
+                            self.redraw_line();
                         }
                     },
                     EscapesIn::DownArrow => match self.mode {
                         ProcessingMode::Normal => self.beep(),
-                        ProcessingMode::Escape(_) => {
-                            panic!("DownArrow: unexpected 'Escape' mode.");
+                        ProcessingMode::Escape(_) | ProcessingMode::Search(_) => {
+                            panic!("DownArrow: unexpected mode.");
                         }
                         ProcessingMode::History(idx) => {
                             if idx == self.history.len() {
                                 self.beep(); // prev_line
                             } else {
-                                self.mode = ProcessingMode::History(idx + 1);
-                                if idx == (self.history.len() - 1) {
-                                    self.line = self.prev_line.clone();
-                                } else {
-                                    self.line = self.history[idx + 1].clone();
-                                }
-                                self.current_pos = self.line.len() as u32;
+                                self.recall(idx + 1, prev_edit);
                                 self.redraw_line();
                             }
                         }
@@ -348,77 +821,180 @@ impl Term {
                             self.beep();
                             continue;
                         }
-                        self.current_pos -= 1;
-                        self.write(&[0x1b, b'[', b'1', b'D']);
+                        self.move_to(prev_grapheme(&self.line, self.current_pos as usize));
                         continue;
                     }
                     EscapesIn::RightArrow => {
                         if self.current_pos >= (self.line.len() as u32) {
+                            if !self.suggestion.is_empty() {
+                                self.accept_suggestion(self.suggestion.len(), prev_edit);
+                                continue;
+                            }
                             self.beep();
                             continue;
                         }
-                        self.write(&[0x1b, b'[', b'1', b'C']);
-                        self.current_pos += 1;
+                        self.move_to(next_grapheme(&self.line, self.current_pos as usize));
                         continue;
                     }
                     EscapesIn::Backspace => {
+                        if self.vi.recording {
+                            self.vi.last_change.push(127);
+                        }
+                        let pos = self.current_pos as usize;
                         match self.mode {
-                            ProcessingMode::Normal => {}
-                            ProcessingMode::Escape(_) | ProcessingMode::History(_) => {
+                            ProcessingMode::Normal | ProcessingMode::History(_) => {}
+                            ProcessingMode::Escape(_) | ProcessingMode::Search(_) => {
                                 self.mode = ProcessingMode::Normal;
                                 self.show_cursor();
                             }
                         }
-                        if self.current_pos > 0 {
-                            self.current_pos -= 1;
-                            self.line.remove(self.current_pos as usize);
-                            self.write(&[0x1b, b'[', b'1', b'D']);
+                        if pos > 0 {
+                            self.save_undo(Edit::Delete, prev_edit);
+                            let prev = prev_grapheme(&self.line, pos);
+                            self.line.drain(prev..pos);
+                            self.current_pos = prev as u32;
                             self.redraw_line();
                         } else {
                             self.beep();
                         }
                         continue;
                     }
-                    EscapesIn::Delete => {
+                    EscapesIn::Delete | EscapesIn::CtrlD => {
+                        if e == EscapesIn::CtrlD && self.line.is_empty() {
+                            self.write("exit\r\n\x1b[?2004l".as_bytes());
+                            self.term_impl.make_cooked();
+                            return Some("exit".to_owned());
+                        }
                         match self.mode {
-                            ProcessingMode::Normal => {}
-                            ProcessingMode::Escape(_) | ProcessingMode::History(_) => {
+                            ProcessingMode::Normal | ProcessingMode::History(_) => {}
+                            ProcessingMode::Escape(_) | ProcessingMode::Search(_) => {
                                 self.mode = ProcessingMode::Normal;
                                 self.show_cursor();
                             }
                         }
                         if self.current_pos < (self.line.len() as u32) {
-                            self.line.remove(self.current_pos as usize);
+                            self.save_undo(Edit::Delete, prev_edit);
+                            let pos = self.current_pos as usize;
+                            self.line.drain(pos..next_grapheme(&self.line, pos));
                             self.redraw_line();
                         } else {
                             self.beep();
                         }
                     }
-                    EscapesIn::Home => {
-                        if self.current_pos > 0 {
-                            self.current_pos = 0;
-                            let (row, _) = self.get_cursor_pos();
-                            self.move_cursor(row, self.line_start);
-                        }
-                    }
+                    EscapesIn::Home => self.move_to(0),
                     EscapesIn::End => {
-                        if self.current_pos < (self.line.len() as u32) {
-                            self.current_pos = self.line.len() as u32;
-                            let (row, _) = self.get_cursor_pos();
-                            self.move_cursor(row, self.line_start + self.current_pos);
+                        if self.suggestion.is_empty() {
+                            self.move_to(self.line.len());
+                        } else {
+                            self.accept_suggestion(self.suggestion.len(), prev_edit);
                         }
                     }
                     EscapesIn::CtrlC => {
                         match self.mode {
                             ProcessingMode::Normal => {}
-                            ProcessingMode::Escape(_) | ProcessingMode::History(_) => {
+                            ProcessingMode::Escape(_)
+                            | ProcessingMode::History(_)
+                            | ProcessingMode::Search(_) => {
                                 self.mode = ProcessingMode::Normal;
                                 self.show_cursor();
                             }
                         }
+                        self.hide_suggestion();
+                        self.move_to(self.line.len());
                         self.write("^C\n\r".as_bytes());
                         self.start_line();
                     }
+                    EscapesIn::CtrlR | EscapesIn::CtrlS => {
+                        self.start_search(e == EscapesIn::CtrlR);
+                    }
+                    EscapesIn::CtrlG => self.beep(),
+                    EscapesIn::WordLeft => {
+                        let pos = self.word_left(is_word_byte);
+                        self.move_to(pos);
+                    }
+                    EscapesIn::WordRight => {
+                        if self.current_pos as usize == self.line.len()
+                            && !self.suggestion.is_empty()
+                        {
+                            // Accept the next word of the suggestion.
+                            let mut len = 0;
+                            while len < self.suggestion.len()
+                                && !is_word_byte(&self.suggestion[len])
+                            {
+                                len += 1;
+                            }
+                            while len < self.suggestion.len() && is_word_byte(&self.suggestion[len])
+                            {
+                                len += 1;
+                            }
+                            self.accept_suggestion(len, prev_edit);
+                            continue;
+                        }
+                        let pos = self.word_right();
+                        self.move_to(pos);
+                    }
+                    EscapesIn::KillLine => {
+                        self.kill_edit(self.current_pos as usize, self.line.len(), prev_edit);
+                    }
+                    EscapesIn::KillLineBack => {
+                        self.kill_edit(0, self.current_pos as usize, prev_edit);
+                    }
+                    EscapesIn::KillWord => {
+                        self.kill_edit(self.current_pos as usize, self.word_right(), prev_edit);
+                    }
+                    EscapesIn::KillWordBack => {
+                        let start = self.word_left(|c| !c.is_ascii_whitespace());
+                        self.kill_edit(start, self.current_pos as usize, prev_edit);
+                    }
+                    EscapesIn::Transpose => {
+                        self.transpose(prev_edit);
+                    }
+                    EscapesIn::Yank => match self.kill_ring.len() {
+                        0 => self.beep(),
+                        len => {
+                            self.save_undo(Edit::Yank, prev_edit);
+                            self.yank(len - 1, None);
+                        }
+                    },
+                    EscapesIn::YankPop => match prev_yank {
+                        Some((start, idx)) => {
+                            let idx = idx.checked_sub(1).unwrap_or(self.kill_ring.len() - 1);
+                            self.save_undo(Edit::Yank, prev_edit);
+                            self.yank(idx, Some(start));
+                        }
+                        None => self.beep(),
+                    },
+                    EscapesIn::LoneEscape => {
+                        // Enter the vi command state.
+                        if self.vi.recording {
+                            self.vi.last_change.push(0x1b);
+                            self.vi.recording = false;
+                        }
+                        self.vi.command = true;
+                        if self.current_pos > 0 {
+                            self.move_to(prev_grapheme(&self.line, self.current_pos as usize));
+                        }
+                        self.show_cursor();
+                    }
+                    EscapesIn::Undo => self.undo(),
+                    EscapesIn::Redo => self.redo(),
+                    EscapesIn::PasteStart => self.paste(prev_edit),
+                    EscapesIn::ClearScreen => {
+                        self.write("\x1b[2J\x1b[H".as_bytes());
+                        self.cursor_row = 0;
+                        self.redisplay();
+                    }
+                    EscapesIn::Tab => {
+                        match self.mode {
+                            ProcessingMode::Normal | ProcessingMode::History(_) => {}
+                            ProcessingMode::Escape(_) | ProcessingMode::Search(_) => {
+                                self.mode = ProcessingMode::Normal;
+                                self.show_cursor();
+                            }
+                        }
+                        self.complete(prev_key_tab);
+                        self.last_key_tab = true;
+                    }
                 },
                 ProcessByteResult::Clear => {
                     self.beep();
@@ -430,6 +1006,1102 @@ impl Term {
         None
     }
 
+    // Show history entry `idx` (or, for history.len(), the line typed before
+    // the Up arrow), keeping the edits of the current entry.
+    fn recall(&mut self, idx: usize, prev_edit: Option<Edit>) {
+        self.save_undo(Edit::Recall, prev_edit);
+        let line = std::mem::take(&mut self.line);
+        match self.mode {
+            ProcessingMode::History(current) if current < self.history.len() => {
+                if line == self.history[current] {
+                    self.edited_history.remove(&current);
+                } else {
+                    self.edited_history.insert(current, line);
+                }
+            }
+            _ => self.prev_line = line,
+        }
+        self.line = if idx == self.history.len() {
+            self.prev_line.clone()
+        } else {
+            self.edited_history
+                .get(&idx)
+                .unwrap_or(&self.history[idx])
+                .clone()
+        };
+        self.current_pos = self.line.len() as u32;
+        let was_normal = self.mode == ProcessingMode::Normal;
+        self.mode = ProcessingMode::History(idx);
+        if was_normal {
+            self.show_cursor();
+        }
+    }
+
+    // Save the line before an edit.
+    fn save_undo(&mut self, edit: Edit, prev_edit: Option<Edit>) {
+        self.last_edit = Some(edit);
+        if self.vi.recording || (prev_edit == Some(edit) && edit != Edit::Vi) {
+            return; // Text inserted by a vi command is a part of the command.
+        }
+        let snapshot = self.snapshot();
+        self.undo.push(snapshot);
+        self.redo.clear();
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            line: self.line.clone(),
+            pos: self.current_pos,
+            mode: self.mode.clone(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.line = snapshot.line;
+        self.current_pos = snapshot.pos;
+        self.mode = snapshot.mode;
+        self.show_cursor();
+        self.redraw_line();
+    }
+
+    fn undo(&mut self) {
+        match self.undo.pop() {
+            Some(snapshot) => {
+                let current = self.snapshot();
+                self.redo.push(current);
+                self.restore(snapshot);
+            }
+            None => self.beep(),
+        }
+    }
+
+    fn redo(&mut self) {
+        match self.redo.pop() {
+            Some(snapshot) => {
+                let current = self.snapshot();
+                self.undo.push(current);
+                self.restore(snapshot);
+            }
+            None => self.beep(),
+        }
+    }
+
+    // Where the cursor goes for `pos` in the line, relative to where the input starts.
+    fn cursor_at(&self, pos: usize) -> (u32, u32) {
+        layout(
+            &self.line,
+            (self.line_start - 1) % self.cols,
+            self.cols,
+            pos,
+        )
+    }
+
+    // Move the cursor within the line.
+    fn move_to(&mut self, pos: usize) {
+        let from = self.cursor_at(self.current_pos as usize);
+        let to = self.cursor_at(pos);
+        self.move_between(from, to);
+        self.current_pos = pos as u32;
+    }
+
+    fn move_between(&mut self, from: (u32, u32), to: (u32, u32)) {
+        let mut bytes = String::new();
+        if to.0 < from.0 {
+            bytes.push_str(format!("\x1b[{}A", from.0 - to.0).as_str());
+        } else if to.0 > from.0 {
+            bytes.push_str(format!("\x1b[{}B", to.0 - from.0).as_str());
+        }
+        if to.1 < from.1 {
+            bytes.push_str(format!("\x1b[{}D", from.1 - to.1).as_str());
+        } else if to.1 > from.1 {
+            bytes.push_str(format!("\x1b[{}C", to.1 - from.1).as_str());
+        }
+        self.write(bytes.as_bytes());
+        self.cursor_row = to.0;
+    }
+
+    // The line has just been written up to the cursor: a terminal only wraps
+    // when the next character arrives, so move to the next row if the last
+    // one is full.
+    fn wrap_cursor(&mut self) {
+        let (row, col) = self.cursor_at(self.current_pos as usize);
+        if row > self.cursor_row && col == 0 {
+            self.write("\r\n".as_bytes());
+        }
+        self.cursor_row = row;
+    }
+
+    // The line (and the suggestion) has just been written: put the cursor where it belongs.
+    fn place_cursor(&mut self) {
+        let mut shown = self.line.clone();
+        shown.extend_from_slice(&self.suggestion);
+        let end = layout(
+            &shown,
+            (self.line_start - 1) % self.cols,
+            self.cols,
+            shown.len(),
+        );
+        if end.0 > 0 && end.1 == 0 && !shown.ends_with(b"\n") {
+            self.write("\r\n".as_bytes()); // See wrap_cursor().
+        }
+        self.move_between(end, self.cursor_at(self.current_pos as usize));
+    }
+
+    // Clear the prompt and all rows of the line, leaving the cursor where the prompt starts.
+    fn clear_input(&mut self) {
+        let rows = self.prompt_rows + self.cursor_row;
+        if rows > 0 {
+            self.write(format!("\x1b[{rows}A").as_bytes());
+        }
+        self.write("\r\x1b[J".as_bytes());
+        self.prompt_rows = 0;
+        self.cursor_row = 0;
+        self.rprompt_shown = false;
+        self.suggestion.clear();
+    }
+
+    // The rest of the latest history entry that starts with the line.
+    fn find_suggestion(&self) -> Vec<u8> {
+        if self.line.is_empty() || self.continuation || self.mode != ProcessingMode::Normal {
+            return vec![];
+        }
+        self.history
+            .iter()
+            .rev()
+            .find(|entry| entry.len() > self.line.len() && entry.starts_with(&self.line))
+            .map(|entry| entry[self.line.len()..].to_vec())
+            .unwrap_or_default()
+    }
+
+    // Append the first `len` bytes of the suggestion to the line.
+    fn accept_suggestion(&mut self, len: usize, prev_edit: Option<Edit>) {
+        self.save_undo(Edit::Insert, prev_edit);
+        let accepted: Vec<u8> = self.suggestion.drain(..len).collect();
+        self.line.extend_from_slice(&accepted);
+        self.current_pos = self.line.len() as u32;
+        self.redraw_line();
+    }
+
+    // Read pasted text, up to the end of the bracketed paste.
+    fn read_paste(&mut self) -> String {
+        const PASTE_END: &[u8] = b"\x1b[201~";
+        let mut bytes = vec![];
+        while !bytes.ends_with(PASTE_END) {
+            bytes.push(self.next_byte());
+        }
+        bytes.truncate(bytes.len() - PASTE_END.len());
+
+        // Terminals send line breaks as CR; other control characters
+        // (e.g. escape sequences) would mess up the screen.
+        let text = String::from_utf8_lossy(&bytes)
+            .replace("\r\n", "\n")
+            .replace('\r', "\n");
+        text.chars()
+            .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+            .collect()
+    }
+
+    // Insert pasted text as is, newlines included: it is run only when
+    // Enter is pressed, and then line by line.
+    fn paste(&mut self, prev_edit: Option<Edit>) {
+        let text = self.read_paste();
+        if self.vi.recording {
+            self.vi.last_change.extend_from_slice(text.as_bytes());
+        }
+        self.save_undo(Edit::Paste, prev_edit);
+        let pos = self.current_pos as usize;
+        self.line.splice(pos..pos, text.bytes());
+        self.current_pos = (pos + text.len()) as u32;
+        self.redraw_line();
+    }
+
+    // E.g. before output goes below the line.
+    fn hide_suggestion(&mut self) {
+        if !self.suggestion.is_empty() {
+            self.suggestion.clear();
+            self.draw_line();
+        }
+    }
+
+    // The start of the word before the cursor.
+    fn word_left(&self, is_word: fn(&u8) -> bool) -> usize {
+        let mut pos = self.current_pos as usize;
+        while pos > 0 && !is_word(&self.line[pos - 1]) {
+            pos -= 1;
+        }
+        while pos > 0 && is_word(&self.line[pos - 1]) {
+            pos -= 1;
+        }
+        pos
+    }
+
+    // The end of the (alphanumeric) word after the cursor.
+    fn word_right(&self) -> usize {
+        let mut pos = self.current_pos as usize;
+        while pos < self.line.len() && !is_word_byte(&self.line[pos]) {
+            pos += 1;
+        }
+        while pos < self.line.len() && is_word_byte(&self.line[pos]) {
+            pos += 1;
+        }
+        pos
+    }
+
+    // A kill command: consecutive kills are undone together
+    // and add to the same kill ring entry.
+    fn kill_edit(&mut self, from: usize, to: usize, prev_edit: Option<Edit>) {
+        if from >= to {
+            self.last_edit = prev_edit;
+            self.beep();
+            return;
+        }
+        self.save_undo(Edit::Kill, prev_edit);
+        self.kill(from, to, prev_edit == Some(Edit::Kill));
+    }
+
+    // Delete line[from..to] into the kill ring and leave the cursor at `from`.
+    // If `append`, add the text to the last kill ring entry.
+    fn kill(&mut self, from: usize, to: usize, append: bool) {
+        if from >= to {
+            self.beep();
+            return;
+        }
+        let killed: Vec<u8> = self.line.drain(from..to).collect();
+        match self.kill_ring.last_mut() {
+            Some(last) if append => {
+                if to == self.current_pos as usize {
+                    // Killing backwards: prepend.
+                    last.splice(0..0, killed);
+                } else {
+                    last.extend_from_slice(&killed);
+                }
+            }
+            _ => self.add_to_kill_ring(killed),
+        }
+        self.current_pos = from as u32;
+        self.redraw_line();
+    }
+
+    fn add_to_kill_ring(&mut self, text: Vec<u8>) {
+        if self.kill_ring.len() == KILL_RING_SIZE {
+            self.kill_ring.remove(0);
+        }
+        self.kill_ring.push(text);
+    }
+
+    // Insert kill_ring[idx] at the cursor or, for Alt-Y, in place of the text
+    // yanked at `replace` by the previous Ctrl-Y/Alt-Y.
+    fn yank(&mut self, idx: usize, replace: Option<usize>) {
+        let start = match replace {
+            Some(start) => {
+                self.line.drain(start..(self.current_pos as usize));
+                start
+            }
+            None => self.current_pos as usize,
+        };
+        let text = self.kill_ring[idx].clone();
+        self.current_pos = (start + text.len()) as u32;
+        self.line.splice(start..start, text);
+        self.last_yank = Some((start, idx));
+        self.redraw_line();
+    }
+
+    // Swap the characters around the cursor (the last two at the end of the line).
+    fn transpose(&mut self, prev_edit: Option<Edit>) {
+        let mut pos = self.current_pos as usize;
+        if pos == 0 || next_grapheme(&self.line, 0) == self.line.len() {
+            self.beep();
+            return;
+        }
+        self.save_undo(Edit::Transpose, prev_edit);
+        if pos == self.line.len() {
+            pos = prev_grapheme(&self.line, pos);
+        }
+        let start = prev_grapheme(&self.line, pos);
+        let end = next_grapheme(&self.line, pos);
+        let mut swapped = self.line[pos..end].to_vec();
+        swapped.extend_from_slice(&self.line[start..pos]);
+        self.line.splice(start..end, swapped);
+        self.current_pos = end as u32;
+        self.redraw_line();
+    }
+
+    // Process a key in the vi command state. Returns the keys that are
+    // processed as usual (e.g. Enter or the arrows), possibly translated.
+    fn process_vi_key(&mut self, result: ProcessByteResult) -> Option<ProcessByteResult> {
+        if self.vi.search.is_some() {
+            self.process_vi_search_key(result);
+            return None;
+        }
+
+        let c = match result {
+            ProcessByteResult::Char(c) if c.is_ascii() => c as u8,
+            ProcessByteResult::Char(_) => {
+                // Vi commands and their arguments are ASCII.
+                self.vi.keys.clear();
+                self.beep();
+                return None;
+            }
+            ProcessByteResult::Escape(EscapesIn::LoneEscape) => {
+                self.vi.keys.clear();
+                return None;
+            }
+            ProcessByteResult::Escape(EscapesIn::Backspace) if self.vi.keys.is_empty() => b'h',
+            ProcessByteResult::Escape(EscapesIn::CtrlR) if self.vi.keys.is_empty() => {
+                return Some(ProcessByteResult::Escape(EscapesIn::Redo));
+            }
+            result => {
+                self.vi.keys.clear();
+                return Some(result);
+            }
+        };
+        self.vi.keys.push(c);
+        let command = parse_vi_command(&self.vi.keys)?;
+        let keys = std::mem::take(&mut self.vi.keys);
+        self.run_vi_command(command, keys)
+    }
+
+    fn run_vi_command(&mut self, command: ViCommand, keys: Vec<u8>) -> Option<ProcessByteResult> {
+        let ViCommand {
+            count,
+            op,
+            mut key,
+            arg,
+        } = command;
+        let pos = self.current_pos as usize;
+        let len = self.line.len();
+
+        if let Some(op) = op {
+            if op == b'c' && key == b'w' && pos < len && vi_class(self.line[pos]) != 0 {
+                key = b'e'; // "cw" changes to the end of the word, like "ce".
+            }
+            let range = if key == op {
+                Some((0, len)) // dd, cc, yy.
+            } else {
+                self.vi_motion(key, arg, count).map(|(target, inclusive)| {
+                    if target < pos {
+                        (target, pos)
+                    } else {
+                        let end = if inclusive {
+                            next_grapheme(&self.line, target)
+                        } else {
+                            target
+                        };
+                        (pos, end.min(len))
+                    }
+                })
+            };
+            let Some((from, to)) = range else {
+                self.beep();
+                return None;
+            };
+            if op == b'y' {
+                if from < to {
+                    self.add_to_kill_ring(self.line[from..to].to_vec());
+                }
+                self.move_to(from);
+                return None;
+            }
+            self.vi_change(keys);
+            self.kill(from, to, false);
+            if op == b'c' {
+                self.vi_insert();
+            }
+            return None;
+        }
+
+        let translated = |op, key| ViCommand {
+            count,
+            op: Some(op),
+            key,
+            arg: None,
+        };
+        match key {
+            b'h' | b'l' | b'w' | b'b' | b'e' | b'0' | b'^' | b'$' | b'f' | b'F' | b't' | b'T' => {
+                match self.vi_motion(key, arg, count) {
+                    Some((target, _)) => self.move_to(target.min(prev_grapheme(&self.line, len))),
+                    None => self.beep(),
+                }
+            }
+            b'i' | b'a' | b'I' | b'A' => {
+                self.vi_change(keys);
+                let target = match key {
+                    b'i' => pos,
+                    b'a' => next_grapheme(&self.line, pos),
+                    b'I' => self.vi_motion(b'^', None, 1).unwrap().0,
+                    _ => len,
+                };
+                self.move_to(target);
+                self.vi_insert();
+            }
+            b'x' => return self.run_vi_command(translated(b'd', b'l'), keys),
+            b'X' => return self.run_vi_command(translated(b'd', b'h'), keys),
+            b's' => return self.run_vi_command(translated(b'c', b'l'), keys),
+            b'D' => return self.run_vi_command(translated(b'd', b'$'), keys),
+            b'C' => return self.run_vi_command(translated(b'c', b'$'), keys),
+            b'S' => return self.run_vi_command(translated(b'c', b'c'), keys),
+            b'p' | b'P' => {
+                let Some(text) = self.kill_ring.last().cloned() else {
+                    self.beep();
+                    return None;
+                };
+                self.vi_change(keys);
+                let at = if key == b'p' {
+                    next_grapheme(&self.line, pos)
+                } else {
+                    pos
+                };
+                let text = text.repeat(count);
+                let end = at + text.len();
+                self.line.splice(at..at, text);
+                self.current_pos = prev_grapheme(&self.line, end) as u32;
+                self.redraw_line();
+            }
+            b'r' => {
+                let mut end = pos;
+                for _ in 0..count {
+                    if end == len {
+                        self.beep();
+                        return None;
+                    }
+                    end = next_grapheme(&self.line, end);
+                }
+                self.vi_change(keys);
+                self.line
+                    .splice(pos..end, std::iter::repeat_n(arg.unwrap(), count));
+                self.current_pos = (pos + count - 1) as u32;
+                self.redraw_line();
+            }
+            b'u' => self.undo(),
+            b'.' => {
+                if self.vi.last_change.is_empty() {
+                    self.beep();
+                }
+                for c in self.vi.last_change.iter().rev() {
+                    self.incoming.push_front(*c);
+                }
+            }
+            b'k' => return Some(ProcessByteResult::Escape(EscapesIn::UpArrow)),
+            b'j' => return Some(ProcessByteResult::Escape(EscapesIn::DownArrow)),
+            b'/' => {
+                self.vi.search = Some(vec![]);
+                self.clear_input();
+                self.write("/".as_bytes());
+            }
+            b'n' | b'N' => {
+                if self.vi_search_history(key == b'n') {
+                    self.redisplay();
+                } else {
+                    self.beep();
+                }
+            }
+            _ => self.beep(),
+        }
+        None
+    }
+
+    // Where a vi motion moves the cursor, and whether an operator includes
+    // the character at the target.
+    fn vi_motion(&self, key: u8, arg: Option<u8>, count: usize) -> Option<(usize, bool)> {
+        let line = &self.line;
+        let len = line.len();
+        let mut pos = self.current_pos as usize;
+        match key {
+            b'h' if pos > 0 => {
+                for _ in 0..count.min(len) {
+                    pos = prev_grapheme(line, pos);
+                }
+                Some((pos, false))
+            }
+            b'l' if pos < len => {
+                for _ in 0..count.min(len) {
+                    pos = next_grapheme(line, pos);
+                }
+                Some((pos, false))
+            }
+            b'0' => Some((0, false)),
+            b'^' => Some((
+                line.iter()
+                    .position(|c| !c.is_ascii_whitespace())
+                    .unwrap_or(len),
+                false,
+            )),
+            b'$' => Some((prev_grapheme(line, len), true)),
+            b'w' => {
+                for _ in 0..count.min(len) {
+                    if pos < len && vi_class(line[pos]) != 0 {
+                        let class = vi_class(line[pos]);
+                        while pos < len && vi_class(line[pos]) == class {
+                            pos += 1;
+                        }
+                    }
+                    while pos < len && vi_class(line[pos]) == 0 {
+                        pos += 1;
+                    }
+                }
+                Some((pos, false))
+            }
+            b'b' => {
+                for _ in 0..count.min(len) {
+                    while pos > 0 && vi_class(line[pos - 1]) == 0 {
+                        pos -= 1;
+                    }
+                    if pos > 0 {
+                        let class = vi_class(line[pos - 1]);
+                        while pos > 0 && vi_class(line[pos - 1]) == class {
+                            pos -= 1;
+                        }
+                    }
+                }
+                Some((pos, false))
+            }
+            b'e' => {
+                for _ in 0..count.min(len) {
+                    if pos + 1 >= len {
+                        break;
+                    }
+                    pos += 1;
+                    while pos + 1 < len && vi_class(line[pos]) == 0 {
+                        pos += 1;
+                    }
+                    let class = vi_class(line[pos]);
+                    while pos + 1 < len && vi_class(line[pos + 1]) == class {
+                        pos += 1;
+                    }
+                }
+                Some((prev_grapheme(line, pos + 1), true))
+            }
+            b'f' | b't' => {
+                let arg = arg?;
+                for _ in 0..count.min(len) {
+                    pos += 1 + line.get((pos + 1)..)?.iter().position(|c| *c == arg)?;
+                }
+                Some((
+                    if key == b't' {
+                        prev_grapheme(line, pos)
+                    } else {
+                        pos
+                    },
+                    true,
+                ))
+            }
+            b'F' | b'T' => {
+                let arg = arg?;
+                for _ in 0..count.min(len) {
+                    pos = line[..pos].iter().rposition(|c| *c == arg)?;
+                }
+                Some((if key == b'T' { pos + 1 } else { pos }, false))
+            }
+            _ => None,
+        }
+    }
+
+    // Before a vi change: save the line for 'u' and the keys for '.'.
+    fn vi_change(&mut self, keys: Vec<u8>) {
+        self.save_undo(Edit::Vi, None);
+        self.vi.last_change = keys;
+    }
+
+    // Enter the vi insert state; the inserted text is a part of the change.
+    fn vi_insert(&mut self) {
+        self.vi.command = false;
+        self.vi.recording = true;
+        self.show_cursor();
+    }
+
+    // Process a key while typing a vi '/' history search.
+    fn process_vi_search_key(&mut self, result: ProcessByteResult) {
+        match result {
+            ProcessByteResult::Char(c) => {
+                let mut buf = [0_u8; 4];
+                let bytes = c.encode_utf8(&mut buf).as_bytes();
+                self.vi.search.as_mut().unwrap().extend_from_slice(bytes);
+                self.write(bytes);
+            }
+            ProcessByteResult::Escape(EscapesIn::Backspace) => {
+                let query = self.vi.search.as_mut().unwrap();
+                if !query.is_empty() {
+                    let prev = prev_grapheme(query, query.len());
+                    let width = display_width(&query[prev..]);
+                    query.truncate(prev);
+                    self.write(format!("\x1b[{width}D\x1b[K").as_bytes());
+                } else {
+                    self.vi.search = None;
+                    self.redisplay();
+                }
+            }
+            ProcessByteResult::Newline => {
+                let query = self.vi.search.take().unwrap();
+                if !query.is_empty() {
+                    self.last_search = query;
+                }
+                if !self.vi_search_history(true) {
+                    self.beep();
+                }
+                self.redisplay();
+            }
+            ProcessByteResult::Escape(EscapesIn::LoneEscape | EscapesIn::CtrlC) => {
+                self.vi.search = None;
+                self.redisplay();
+            }
+            ProcessByteResult::Escape(EscapesIn::PasteStart) => {
+                // The query is a single line.
+                let text = self.read_paste().replace(['\n', '\t'], " ");
+                let query = self.vi.search.as_mut().unwrap();
+                query.extend_from_slice(text.as_bytes());
+                self.write(text.as_bytes());
+            }
+            ProcessByteResult::Continue => {}
+            _ => self.beep(),
+        }
+    }
+
+    // Show the previous (or, if !reverse, the next) history entry containing
+    // the last search query. Returns false if there is none.
+    fn vi_search_history(&mut self, reverse: bool) -> bool {
+        let query = self.last_search.as_slice();
+        if query.is_empty() {
+            return false;
+        }
+        let current = match self.mode {
+            ProcessingMode::History(idx) => idx,
+            _ => self.history.len(),
+        };
+        let contains = |idx: &usize| {
+            self.history[*idx]
+                .windows(query.len())
+                .any(|window| window == query)
+        };
+        let found = if reverse {
+            (0..current).rev().find(contains)
+        } else {
+            ((current + 1)..self.history.len()).find(contains)
+        };
+        let Some(idx) = found else {
+            return false;
+        };
+
+        self.recall(idx, None);
+        self.current_pos = 0;
+        true
+    }
+
+    // Complete the word before the cursor. If the completion is ambiguous,
+    // beep or, if `list` (i.e. on the second TAB), list the candidates.
+    fn complete(&mut self, list: bool) {
+        let Ok(line) = std::str::from_utf8(&self.line[..]) else {
+            self.beep();
+            return;
+        };
+        let completion = crate::complete::complete(line, self.current_pos as usize);
+
+        if let Some(insertion) = completion.insertion() {
+            self.save_undo(Edit::Complete, None);
+            let mut line = Vec::from(&self.line[..completion.start]);
+            line.extend_from_slice(insertion.as_bytes());
+            let pos = line.len();
+            line.extend_from_slice(&self.line[(self.current_pos as usize)..]);
+            self.line = line;
+            self.current_pos = pos as u32;
+            self.redraw_line();
+            return;
+        }
+
+        if completion.candidates.is_empty() || !list {
+            self.beep();
+            return;
+        }
+
+        let items: Vec<&str> = completion
+            .candidates
+            .iter()
+            .map(|candidate| candidate.display.as_str())
+            .collect();
+        // List the candidates below the line.
+        self.hide_suggestion();
+        let (pos, end) = (self.current_pos as usize, self.line.len());
+        self.move_between(self.cursor_at(pos), self.cursor_at(end));
+        self.cursor_row = 0;
+        if items.len() > 100 {
+            self.write(
+                format!("\r\nDisplay all {} possibilities? (y or n)", items.len()).as_bytes(),
+            );
+            if self.next_byte() != b'y' {
+                self.write("\r\n".as_bytes());
+                self.redisplay();
+                return;
+            }
+        }
+
+        // Print the candidates in columns, sorted down the columns like ls.
+        let col_width = items.iter().map(|item| item.width()).max().unwrap() + 2;
+        let cols = (self.cols as usize / col_width).max(1);
+        let rows = items.len().div_ceil(cols);
+        let mut output = String::from("\r\n");
+        for row in 0..rows {
+            for col in 0..cols {
+                let Some(item) = items.get(col * rows + row) else {
+                    break;
+                };
+                output.push_str(item);
+                if col + 1 < cols && (col + 1) * rows + row < items.len() {
+                    let padding = col_width - item.width();
+                    output.extend(std::iter::repeat_n(' ', padding));
+                }
+            }
+            output.push_str("\r\n");
+        }
+        self.write(output.as_bytes());
+        self.redisplay();
+    }
+
+    fn start_search(&mut self, reverse: bool) {
+        self.mode = ProcessingMode::Search(Search {
+            query: vec![],
+            idx: self.history.len(),
+            reverse,
+            failed: false,
+            orig_line: self.line.clone(),
+            orig_pos: self.current_pos,
+        });
+        self.show_cursor();
+        self.draw_search();
+    }
+
+    // Find the first entry matching the query from history[start] on,
+    // going back if `reverse`, and show it.
+    fn search_from(&mut self, start: usize, reverse: bool) {
+        let ProcessingMode::Search(search) = &mut self.mode else {
+            panic!("search_from: not searching.");
+        };
+        search.reverse = reverse;
+
+        let query = search.query.as_slice();
+        let find = |entry: &[u8]| {
+            if query.is_empty() || query.len() > entry.len() {
+                return None;
+            }
+            entry.windows(query.len()).position(|w| w == query)
+        };
+        let mut indices: Box<dyn Iterator<Item = usize>> = if reverse {
+            Box::new((0..(start + 1).min(self.history.len())).rev())
+        } else {
+            Box::new(start..self.history.len())
+        };
+
+        match indices.find_map(|idx| find(&self.history[idx]).map(|pos| (idx, pos))) {
+            Some((idx, pos)) => {
+                search.idx = idx;
+                search.failed = false;
+                self.line = self.history[idx].clone();
+                self.current_pos = pos as u32;
+            }
+            None => {
+                search.failed = true;
+                self.beep();
+            }
+        }
+        self.draw_search();
+    }
+
+    // Process a key in the search mode; return false if the key ends the search.
+    fn process_search_key(&mut self, result: &ProcessByteResult) -> bool {
+        let ProcessingMode::Search(search) = &mut self.mode else {
+            panic!("process_search_key: not searching.");
+        };
+        let idx = search.idx;
+
+        match result {
+            ProcessByteResult::Continue => {}
+            ProcessByteResult::Char(c) => {
+                let mut buf = [0_u8; 4];
+                search
+                    .query
+                    .extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                let reverse = search.reverse;
+                self.search_from(idx.min(self.history.len().saturating_sub(1)), reverse);
+            }
+            ProcessByteResult::Escape(EscapesIn::Backspace) => {
+                if search.query.is_empty() {
+                    self.beep();
+                    return true;
+                }
+                let prev = prev_grapheme(&search.query, search.query.len());
+                search.query.truncate(prev);
+                // Start over with the shorter query.
+                let reverse = search.reverse;
+                if reverse {
+                    self.search_from(self.history.len().saturating_sub(1), true);
+                } else {
+                    self.search_from(0, false);
+                }
+            }
+            ProcessByteResult::Escape(e @ (EscapesIn::CtrlR | EscapesIn::CtrlS)) => {
+                let reverse = *e == EscapesIn::CtrlR;
+                if search.query.is_empty() {
+                    // Repeat the previous search.
+                    search.query = self.last_search.clone();
+                    let start = if reverse { idx.saturating_sub(1) } else { 0 };
+                    self.search_from(start, reverse);
+                } else if reverse && idx > 0 && idx <= self.history.len() {
+                    self.search_from(idx - 1, true);
+                } else if !reverse && idx + 1 < self.history.len() {
+                    self.search_from(idx + 1, false);
+                } else {
+                    self.beep();
+                }
+            }
+            ProcessByteResult::Escape(EscapesIn::PasteStart) => {
+                // The query is a single line.
+                let text = self.read_paste().replace(['\n', '\t'], " ");
+                let ProcessingMode::Search(search) = &mut self.mode else {
+                    unreachable!();
+                };
+                search.query.extend_from_slice(text.as_bytes());
+                let reverse = search.reverse;
+                self.search_from(idx.min(self.history.len().saturating_sub(1)), reverse);
+            }
+            ProcessByteResult::Escape(EscapesIn::CtrlG) => self.end_search(false),
+            _ => return false,
+        }
+        true
+    }
+
+    fn end_search(&mut self, accept: bool) {
+        let ProcessingMode::Search(search) =
+            std::mem::replace(&mut self.mode, ProcessingMode::Normal)
+        else {
+            panic!("end_search: not searching.");
+        };
+        if !search.query.is_empty() {
+            self.last_search = search.query;
+        }
+
+        if accept && search.idx < self.history.len() {
+            // Continue from the found entry with Up/Down arrows.
+            self.mode = ProcessingMode::History(search.idx);
+            self.undo.push(Snapshot {
+                line: search.orig_line.clone(),
+                pos: search.orig_pos,
+                mode: ProcessingMode::Normal,
+            });
+            self.redo.clear();
+            self.last_edit = Some(Edit::Recall);
+            self.prev_line = search.orig_line;
+        } else if !accept {
+            self.line = search.orig_line;
+            self.current_pos = search.orig_pos;
+        }
+        self.show_cursor();
+        self.redisplay();
+    }
+
+    // Show "(reverse-i-search)'query': line" with the match highlighted.
+    fn draw_search(&mut self) {
+        let ProcessingMode::Search(search) = &self.mode else {
+            panic!("draw_search: not searching.");
+        };
+
+        let mut bytes = Vec::from("(".as_bytes());
+        if search.failed {
+            bytes.extend_from_slice("failed ".as_bytes());
+        }
+        if search.reverse {
+            bytes.extend_from_slice("reverse-".as_bytes());
+        }
+        bytes.extend_from_slice("i-search)'".as_bytes());
+        bytes.extend_from_slice(&search.query);
+        bytes.extend_from_slice("': ".as_bytes());
+        // The line is laid out after the search prompt, as after the usual one.
+        let line_start = display_width(&bytes) + 1;
+
+        let pos = self.current_pos as usize;
+        if search.idx < self.history.len() && !search.query.is_empty() {
+            let end = (pos + search.query.len()).min(self.line.len());
+            bytes.extend_from_slice(&self.line[..pos]);
+            bytes.extend_from_slice("\x1b[7m".as_bytes());
+            bytes.extend_from_slice(&self.line[pos..end]);
+            bytes.extend_from_slice("\x1b[0m".as_bytes());
+            bytes.extend_from_slice(&self.line[end..]);
+        } else {
+            bytes.extend_from_slice(&self.line);
+        }
+        self.clear_input();
+        self.line_start = line_start;
+        self.write(&with_crlf(&bytes));
+        self.place_cursor();
+    }
+
+    // Print the prompt and the line again, e.g. after listing completions.
+    fn redisplay(&mut self) {
+        self.clear_input();
+        self.draw_prompt();
+        self.suggestion = self.find_suggestion();
+        self.draw_rprompt();
+        self.write_line();
+    }
+
+    // Whether the line is shown with syntax highlighting. Continued lines
+    // are not: how they lex depends on the previous ones.
+    fn is_highlighted(&self) -> bool {
+        !self.continuation && crate::highlight::is_enabled()
+    }
+
+    // Write the line and the suggestion after the prompt, then place the cursor.
+    fn write_line(&mut self) {
+        let mut bytes = self.highlighted();
+        if !self.suggestion.is_empty() {
+            bytes.extend_from_slice("\x1b[2m".as_bytes());
+            bytes.extend_from_slice(&self.suggestion);
+            bytes.extend_from_slice("\x1b[0m".as_bytes());
+        }
+        self.write(&with_crlf(&bytes));
+        self.place_cursor();
+    }
+
+    // The line as shown.
+    fn highlighted(&self) -> Vec<u8> {
+        if !self.is_highlighted() {
+            return self.line.clone();
+        }
+        match std::str::from_utf8(&self.line) {
+            Ok(line) => crate::highlight::highlight(line).into_bytes(),
+            Err(_) => self.line.clone(),
+        }
+    }
+
+    // Show RPROMPT at the right end of the input's first row, unless the
+    // line reaches it. The cursor is where the input starts, with nothing after it.
+    fn draw_rprompt(&mut self) {
+        self.rprompt_shown = !self.rprompt.is_empty() && self.rprompt_fits();
+        if !self.rprompt_shown {
+            return;
+        }
+        let width = crate::prompt::width(self.rprompt.as_str()) as u32;
+        let start = (self.line_start - 1) % self.cols;
+        let mut bytes = format!("\x1b[{}C{}\r", self.cols - width - start, self.rprompt);
+        if start > 0 {
+            bytes.push_str(format!("\x1b[{start}C").as_str());
+        }
+        self.write(bytes.as_bytes());
+    }
+
+    // Whether the line and the suggestion leave a blank column before RPROMPT.
+    fn rprompt_fits(&self) -> bool {
+        let width = crate::prompt::width(self.rprompt.as_str()) as u32;
+        let mut shown = self.line.clone();
+        shown.extend_from_slice(&self.suggestion);
+        let (row, col) = layout(
+            &shown,
+            (self.line_start - 1) % self.cols,
+            self.cols,
+            shown.len(),
+        );
+        row == 0 && col + width < self.cols
+    }
+
+    // Replace the prompt of the accepted line with $TRANSIENT_PROMPT.
+    fn draw_transient_prompt(&mut self) {
+        let prompt = crate::vars::get("TRANSIENT_PROMPT").unwrap_or_else(|| "$ ".to_owned());
+        let prompt = crate::prompt::expand(prompt.as_str());
+        self.clear_input();
+        let last = prompt.rsplit('\n').next().unwrap_or_default();
+        self.line_start = crate::prompt::width(last) as u32 + 1;
+        self.write(format!("\r{prompt}").as_bytes());
+        self.current_pos = self.line.len() as u32;
+        self.write_line();
+    }
+
+    // Expand the prompts for a new line: PS1, or PS2 if the command
+    // continues on this line, and RPROMPT. Redraws show them as they are.
+    fn expand_prompts(&mut self) {
+        self.rprompt = match crate::vars::get("RPROMPT") {
+            Some(rprompt) if !self.continuation => crate::prompt::expand(rprompt.as_str()),
+            _ => String::new(),
+        };
+        self.prompt = if self.continuation {
+            let ps2 = crate::vars::get("PS2").unwrap_or_else(|| "> ".to_owned());
+            crate::prompt::expand(ps2.as_str())
+        } else {
+            match crate::vars::get("PS1") {
+                Some(ps1) => crate::prompt::expand(ps1.as_str()),
+                None => format!("\x1b[32mrush:\x1b[0m {}$ ", crate::prompt()),
+            }
+        };
+    }
+
+    // Print the prompt, see expand_prompts().
+    fn draw_prompt(&mut self) {
+        std::io::stderr().flush().unwrap();
+        let prompt = self.prompt.clone();
+
+        // The input starts after the prompt's last row.
+        let (above, last) = match prompt.rsplit_once('\n') {
+            Some((above, last)) => (Some(above), last),
+            None => (None, prompt.as_str()),
+        };
+        let cols = self.cols as usize;
+        let last_width = crate::prompt::width(last);
+        let mut rows = last_width / cols;
+        for line in above.iter().flat_map(|above| above.split('\n')) {
+            rows += crate::prompt::width(line).div_ceil(cols).max(1);
+        }
+        self.prompt_rows = rows as u32;
+        self.line_start = last_width as u32 + 1;
+
+        // Output processing is on in the raw mode: '\n' starts a new row.
+        self.write(format!("\r{prompt}").as_bytes());
+    }
+
+    fn update_size(&mut self) {
+        let (rows, cols) = match self.term_impl.size() {
+            Some(size) => size,
+            // Asking the terminal takes a round trip: ask once per line at most.
+            None if self.size_queried => return,
+            None => {
+                self.size_queried = true;
+                // Move the cursor as far as it goes and see where it ends up.
+                self.write("\x1b7\x1b[999;999H".as_bytes());
+                let size = self.get_cursor_pos();
+                self.write("\x1b8".as_bytes());
+                match size {
+                    Some(size) => size,
+                    None if self.cols > 0 => (self.rows, self.cols),
+                    None => (24, 80),
+                }
+            }
+        };
+        if (rows, cols) != (self.rows, self.cols) {
+            self.rows = rows;
+            self.cols = cols;
+            // For child processes; readonly values are left alone.
+            for (name, value) in [("COLUMNS", cols), ("LINES", rows)] {
+                if !crate::vars::is_readonly(name) {
+                    let _ = crate::vars::export(&[format!("{name}={value}")]);
+                }
+            }
+        }
+    }
+
+    // The window was resized: lay out the line again.
+    fn on_resize(&mut self) {
+        self.size_queried = false;
+        self.update_size();
+        // Terminals that rewrap rows on resize wrap them as we do.
+        self.cursor_row = self.cursor_at(self.current_pos as usize).0;
+        match self.mode {
+            ProcessingMode::Search(_) => self.draw_search(),
+            _ => match self.vi.search.clone() {
+                Some(query) => {
+                    self.clear_input();
+                    self.write("/".as_bytes());
+                    self.write(&query);
+                }
+                None => self.redisplay(),
+            },
+        }
+    }
+
     fn beep(&mut self) {
         self.write(&[7_u8]); // Beep.
     }
@@ -441,12 +2113,29 @@ impl Term {
     }
 
     fn start_line(&mut self) {
-        let col = prompt();
+        if !self.term_impl.reports_resizes() {
+            // Ask again on each line, or resizes would go unnoticed.
+            self.size_queried = false;
+        }
+        self.update_size(); // In case the window was resized while a command ran.
+        self.expand_prompts();
+        self.draw_prompt();
         self.line.clear();
         self.prev_line.clear();
-        self.line_start = col as u32;
         self.current_pos = 0;
+        self.cursor_row = 0;
+        self.suggestion.clear();
+        self.draw_rprompt();
         self.mode = ProcessingMode::Normal;
+        self.undo.clear();
+        self.redo.clear();
+        self.edited_history.clear(); // Edited history entries revert to what was run.
+        self.vi.keys.clear();
+        self.vi.search = None;
+        if self.vi.command {
+            self.vi.command = false;
+            self.show_cursor();
+        }
     }
 
     fn debug_log(&mut self, _msg: &str) {
@@ -488,10 +2177,20 @@ impl Term {
             Ps = 5  -> blinking bar (xterm).
             Ps = 6  -> steady bar (xterm).
         */
+        if self.vi.command
+            && matches!(
+                self.mode,
+                ProcessingMode::Normal | ProcessingMode::History(_)
+            )
+        {
+            self.write("\x1b[2 q".as_bytes());
+            return;
+        }
         match self.mode {
             ProcessingMode::Normal => self.write("\x1b[5 q".as_bytes()),
             ProcessingMode::Escape(_) => self.write("\x1b[1 q".as_bytes()),
             ProcessingMode::History(_) => self.write("\x1b[2 q".as_bytes()),
+            ProcessingMode::Search(_) => self.write("\x1b[3 q".as_bytes()),
         };
     }
 
@@ -503,20 +2202,29 @@ impl Term {
         self.write(format!("\x1b[{row};{col}H").as_bytes());
     }
 
+    // Redraw the line after it has changed.
     fn redraw_line(&mut self) {
-        let mut stdout_lock = std::io::stdout().lock();
-        let (row, _) = self.get_cursor_pos();
+        self.suggestion = self.find_suggestion();
+        self.draw_line();
+    }
 
+    // Redraw the line with the current suggestion.
+    fn draw_line(&mut self) {
         self.hide_cursor();
-        self.move_cursor(row, self.line_start);
-
-        self.write("\x1b[K".as_bytes());
-
-        // Write to stdout instead of self.write() to avoid borrow checker complaints.
-        stdout_lock.write_all(&self.line[0..]).unwrap();
-        stdout_lock.flush().unwrap();
-
-        self.move_cursor(row, self.line_start + self.current_pos);
+        // The line may span several rows; go back to where it starts.
+        let mut bytes = String::new();
+        if self.cursor_row > 0 {
+            bytes.push_str(format!("\x1b[{}A", self.cursor_row).as_str());
+        }
+        bytes.push('\r');
+        let start = (self.line_start - 1) % self.cols;
+        if start > 0 {
+            bytes.push_str(format!("\x1b[{start}C").as_str());
+        }
+        bytes.push_str("\x1b[J");
+        self.write(bytes.as_bytes());
+        self.draw_rprompt();
+        self.write_line();
         self.show_cursor();
     }
 
@@ -593,7 +2301,7 @@ impl Term {
             }
 
             let mut col = 0_u32;
-            for digit in &bytes[row_col_divider_pos..curr_pos] {
+            for digit in &bytes[(row_col_divider_pos + 1)..curr_pos] {
                 col = col * 10 + ((*digit - b'0') as u32);
             }
 
@@ -609,28 +2317,48 @@ impl Term {
         None
     }
 
-    fn get_cursor_pos(&mut self) -> (u32, u32) {
+    // Ask the terminal where the cursor is. None if the terminal does not
+    // answer in time, in which case it is not asked again.
+    fn get_cursor_pos(&mut self) -> Option<(u32, u32)> {
+        if self.no_cursor_reports {
+            return None;
+        }
         self.write(&[0x1b, b'[', b'6', b'n']); // Query the terminal for cursor position.
 
         let mut incoming_bytes = vec![];
 
         // wait for: 0x1b "[<ROW>;<COL>R"
         loop {
+            if !term_impl::wait_for_input(QUERY_TIMEOUT) {
+                // Keys typed meanwhile are not lost.
+                self.incoming.extend(incoming_bytes);
+                self.no_cursor_reports = true;
+                return None;
+            }
             let mut buf = [0; 32];
-            let sz = std::io::stdin().read(&mut buf).unwrap();
+            let sz = match std::io::stdin().read(&mut buf) {
+                Ok(sz) => sz,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => panic!("stdin() read failed with: {err:?}"),
+            };
             incoming_bytes.extend_from_slice(&buf[0..sz]);
             if let Some(result) = Self::extract_cursor_pos(&mut incoming_bytes) {
                 for c in &incoming_bytes {
                     self.incoming.push_back(*c);
                 }
-                return result;
+                return Some(result);
             }
         }
     }
 
     fn maybe_add_to_history(&mut self, cmd: &str) {
-        if self.history.is_empty() || *self.history.last().unwrap() != cmd.as_bytes() {
-            self.history.push(Vec::from(cmd.as_bytes()));
+        // Pasted lines are run one by one (see main()), and are separate entries.
+        for line in cmd.split('\n').filter(|line| !line.trim().is_empty()) {
+            if self.history.is_empty() || *self.history.last().unwrap() != line.as_bytes() {
+                self.history.push(Vec::from(line.as_bytes()));
+                crate::history::trim(&mut self.history);
+                crate::history::append(line.as_bytes());
+            }
         }
     }
 
@@ -644,22 +2372,6 @@ impl Term {
                     self.move_cursor(1, 1);
                 }
                 self.maybe_add_to_history(cmd);
-                self.start_line();
-
-                true
-            }
-            "history" => {
-                let mut stdout_lock = std::io::stdout().lock();
-                stdout_lock.write_all("\r\n".as_bytes()).unwrap();
-
-                for line in &self.history {
-                    let written = stdout_lock.write(line).unwrap();
-                    assert_eq!(written, line.len());
-                    stdout_lock.write_all("\r\n".as_bytes()).unwrap();
-                }
-                stdout_lock.flush().unwrap();
-                self.maybe_add_to_history(cmd);
-                self.start_line();
 
                 true
             }
@@ -667,7 +2379,6 @@ impl Term {
                 self.debug = !self.debug;
                 self.maybe_add_to_history(cmd);
                 self.write("\r\n".as_bytes());
-                self.start_line();
                 true
             }
             _ => false,
@@ -675,27 +2386,18 @@ impl Term {
     }
 }
 
-fn prompt() -> usize {
-    std::io::stderr().flush().unwrap();
-    let prompt_str = crate::prompt();
-    let bytes = format!("\r\x1b[32mrush:\x1b[0m {prompt_str}$ ");
-
-    let mut stdout = std::io::stdout().lock();
-    stdout.write_all(bytes.as_bytes()).unwrap();
-    stdout.flush().unwrap();
-
-    prompt_str.len() + 9 // "rush: <prompt>$ "
-}
-
 static TERM: Mutex<Option<Term>> = Mutex::new(None);
 
 pub fn init(piped: bool) {
     debug_assert!(TERM.lock().unwrap().is_none());
+    crate::bind::init();
     *TERM.lock().unwrap() = Some(Term::new(piped));
 }
 
-pub fn readline() -> String {
+/// Read a command line; `continuation` if it continues the previous one.
+pub fn readline(continuation: bool) -> String {
     let term = &mut *TERM.lock().unwrap();
+    term.as_mut().unwrap().continuation = continuation;
     loop {
         if let Some(line) = term.as_mut().unwrap().readline() {
             return line;
@@ -703,9 +2405,17 @@ pub fn readline() -> String {
     }
 }
 
+/// The 'history' builtin.
+pub fn history(args: &[String]) -> Result<(), i32> {
+    match &mut *TERM.lock().unwrap() {
+        Some(term) => crate::history::builtin(&mut term.history, args),
+        None => Ok(()), // No history in non-interactive mode.
+    }
+}
+
 pub fn on_exit() {
     if let Some(term) = &mut *TERM.lock().unwrap() {
-        term.write("\x1b[ q".as_bytes()); // Reset the cursor.
+        term.write("\x1b[ q\x1b[?2004l".as_bytes()); // Reset the cursor, no bracketed paste.
         term.term_impl.on_exit();
     }
 }
@@ -1,8 +1,34 @@
 use libc::termios as Termios;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigwinch(_: libc::c_int) {
+    RESIZED.store(true, Ordering::Relaxed);
+}
+
+/// Whether stdin has something to read within `timeout`.
+pub(super) fn wait_for_input(timeout: std::time::Duration) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        match unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) } {
+            -1 if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => {
+                continue
+            }
+            0 => return false,
+            _ => return true, // Including errors: let the read report them.
+        }
+    }
+}
 
 pub(super) struct ArchTerm {
     cooked_termios: Termios,
     raw_termios: Termios,
+    watching_size: bool,
 }
 
 impl ArchTerm {
@@ -28,7 +54,20 @@ impl ArchTerm {
         Self {
             cooked_termios,
             raw_termios,
+            watching_size: false,
+        }
+    }
+
+    // Catch SIGWINCH. Without SA_RESTART, a read from stdin waiting for
+    // a key returns EINTR, so that the line is laid out again right away.
+    fn watch_size(&mut self) {
+        unsafe {
+            let mut action: libc::sigaction = core::mem::zeroed();
+            action.sa_sigaction = on_sigwinch as extern "C" fn(libc::c_int) as usize;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGWINCH, &action, core::ptr::null_mut());
         }
+        self.watching_size = true;
     }
 }
 
@@ -45,6 +84,28 @@ impl super::term::TermImpl for ArchTerm {
         }
     }
 
+    fn size(&mut self) -> Option<(u32, u32)> {
+        // Only the line editor asks, so the client relay is not interrupted.
+        if !self.watching_size {
+            self.watch_size();
+        }
+
+        let mut winsize: libc::winsize = unsafe { core::mem::zeroed() };
+        let res = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut winsize) };
+        if res != 0 || winsize.ws_row == 0 || winsize.ws_col == 0 {
+            return None;
+        }
+        Some((winsize.ws_row as u32, winsize.ws_col as u32))
+    }
+
+    fn resized(&mut self) -> bool {
+        RESIZED.swap(false, Ordering::Relaxed)
+    }
+
+    fn reports_resizes(&self) -> bool {
+        true
+    }
+
     fn on_exit(&mut self) {
         self.make_cooked(); // Restore termios.
     }
@@ -0,0 +1,191 @@
+// Shell variables.
+//
+// Variables live here rather than in the process environment: only
+// exported variables are passed to child processes.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+#[derive(Clone, Default)]
+struct Var {
+    value: Option<String>, // None: declared (e.g. 'export A') but not set.
+    exported: bool,
+    readonly: bool,
+}
+
+static VARS: Mutex<BTreeMap<String, Var>> = Mutex::new(BTreeMap::new());
+
+/// Import the process environment as exported variables.
+pub fn init() {
+    let mut vars = VARS.lock().unwrap();
+    for (name, value) in std::env::vars() {
+        vars.insert(
+            name,
+            Var {
+                value: Some(value),
+                exported: true,
+                readonly: false,
+            },
+        );
+    }
+}
+
+/// Whether `name` is a valid variable name: [A-Za-z_][A-Za-z0-9_]*.
+pub fn is_name(name: &str) -> bool {
+    let mut bytes = name.bytes();
+    match bytes.next() {
+        Some(b) if b.is_ascii_alphabetic() || b == b'_' => {}
+        _ => return false,
+    }
+    bytes.all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+pub fn get(name: &str) -> Option<String> {
+    VARS.lock()
+        .unwrap()
+        .get(name)
+        .and_then(|var| var.value.clone())
+}
+
+pub fn is_readonly(name: &str) -> bool {
+    VARS.lock()
+        .unwrap()
+        .get(name)
+        .is_some_and(|var| var.readonly)
+}
+
+/// Set a variable, keeping its attributes. Fails on readonly variables.
+pub fn set(name: &str, value: &str) -> Result<(), i32> {
+    let mut vars = VARS.lock().unwrap();
+    let var = vars.entry(name.to_owned()).or_default();
+    if var.readonly {
+        eprintln!("rush: {name}: readonly variable.");
+        return Err(1);
+    }
+    var.value = Some(value.to_owned());
+    Ok(())
+}
+
+/// The names of all set variables.
+pub fn names() -> Vec<String> {
+    VARS.lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, var)| var.value.is_some())
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// The environment of child processes.
+pub fn exported() -> Vec<(String, String)> {
+    VARS.lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(name, var)| match &var.value {
+            Some(value) if var.exported => Some((name.clone(), value.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn print_declarations(prefix: &str, filter: impl Fn(&Var) -> bool) {
+    for (name, var) in VARS.lock().unwrap().iter() {
+        if !filter(var) {
+            continue;
+        }
+        match &var.value {
+            Some(value) => println!("{prefix} {name}={}", crate::expand::quote(value)),
+            None => println!("{prefix} {name}"),
+        }
+    }
+}
+
+/// 'set' without arguments: list all variables.
+pub fn print_all() {
+    for (name, var) in VARS.lock().unwrap().iter() {
+        if let Some(value) = &var.value {
+            println!("{name}={}", crate::expand::quote(value));
+        }
+    }
+}
+
+// Process 'NAME' and 'NAME=value' arguments of export/readonly.
+fn declare(cmd: &str, args: &[String], update: impl Fn(&mut Var)) -> Result<(), i32> {
+    let mut result = Ok(());
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !is_name(name) {
+            eprintln!("{cmd}: '{arg}': not a valid identifier.");
+            result = Err(1);
+            continue;
+        }
+        if let Some(value) = value {
+            if set(name, value).is_err() {
+                result = Err(1);
+                continue;
+            }
+        }
+        update(VARS.lock().unwrap().entry(name.to_owned()).or_default());
+    }
+    result
+}
+
+pub fn export(args: &[String]) -> Result<(), i32> {
+    match args.first().map(|arg| arg.as_str()) {
+        None | Some("-p") => {
+            print_declarations("export", |var| var.exported);
+            Ok(())
+        }
+        Some("-n") => declare("export", &args[1..], |var| var.exported = false),
+        Some(_) => declare("export", args, |var| var.exported = true),
+    }
+}
+
+pub fn readonly(args: &[String]) -> Result<(), i32> {
+    match args.first().map(|arg| arg.as_str()) {
+        None | Some("-p") => {
+            print_declarations("readonly", |var| var.readonly);
+            Ok(())
+        }
+        Some(_) => declare("readonly", args, |var| var.readonly = true),
+    }
+}
+
+pub fn unset(args: &[String]) -> Result<(), i32> {
+    let mut names = args;
+    match args.first().map(|arg| arg.as_str()) {
+        Some("-v") => names = &args[1..],
+        Some("-f") => {
+            eprintln!("unset: -f: functions are not supported.");
+            return Err(1);
+        }
+        _ => {}
+    }
+
+    let mut result = Ok(());
+    let mut vars = VARS.lock().unwrap();
+    for name in names {
+        match vars.get(name.as_str()) {
+            Some(var) if var.readonly => {
+                eprintln!("unset: {name}: cannot unset: readonly variable.");
+                result = Err(1);
+            }
+            Some(_) => {
+                vars.remove(name.as_str());
+            }
+            None => {}
+        }
+    }
+    result
+}
+
+/// 'env' without arguments: list the exported variables.
+pub fn env() -> Result<(), i32> {
+    for (name, value) in exported() {
+        println!("{name}={value}");
+    }
+    Ok(())
+}